@@ -0,0 +1,71 @@
+-- Accounts own devices; an inactive account disables all of its devices.
+CREATE TABLE IF NOT EXISTS accounts (
+    id INT NOT NULL AUTO_INCREMENT,
+    name VARCHAR(255) NOT NULL,
+    active TINYINT(1) NOT NULL DEFAULT 1,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id)
+) ENGINE = InnoDB;
+
+CREATE TABLE IF NOT EXISTS devices (
+    id INT NOT NULL AUTO_INCREMENT,
+    mac_address VARCHAR(17) NOT NULL,
+    account_id INT NULL,
+    active TINYINT(1) NOT NULL DEFAULT 1,
+    squelch TINYINT(1) NOT NULL DEFAULT 0,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id),
+    UNIQUE KEY uq_devices_mac_address (mac_address),
+    KEY idx_devices_account_id (account_id),
+    CONSTRAINT fk_devices_account FOREIGN KEY (account_id) REFERENCES accounts (id)
+) ENGINE = InnoDB;
+
+CREATE TABLE IF NOT EXISTS heartbeats (
+    id BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+    device_id INT NOT NULL,
+    mac_address VARCHAR(17) NOT NULL,
+    reported_ip VARCHAR(45) NOT NULL,
+    public_ip VARCHAR(45) NOT NULL,
+    lp INT NULL,
+    device_ts BIGINT NULL,
+    received_at DATETIME(6) NOT NULL,
+    PRIMARY KEY (id),
+    KEY idx_heartbeats_mac_received (mac_address, received_at),
+    KEY idx_heartbeats_received (received_at)
+) ENGINE = InnoDB;
+
+-- Returns one (account_id, squelch) row when the device may report
+-- heartbeats and no rows otherwise. p_msg always says why.
+DELIMITER $$
+CREATE PROCEDURE is_device_active(IN p_mac VARCHAR(17), OUT p_msg VARCHAR(255))
+BEGIN
+    DECLARE v_found TINYINT DEFAULT 0;
+    DECLARE v_device_active TINYINT DEFAULT 0;
+    DECLARE v_account_id INT DEFAULT NULL;
+    DECLARE v_account_active TINYINT DEFAULT 1;
+    DECLARE v_squelch TINYINT DEFAULT 0;
+    DECLARE CONTINUE HANDLER FOR NOT FOUND SET v_found = 0;
+
+    SELECT 1, d.active, d.account_id, COALESCE(a.active, 1), d.squelch
+      INTO v_found, v_device_active, v_account_id, v_account_active, v_squelch
+      FROM devices d
+      LEFT JOIN accounts a ON a.id = d.account_id
+     WHERE d.mac_address = p_mac
+     LIMIT 1;
+
+    IF v_found = 0 THEN
+        SET p_msg = 'unknown device';
+    ELSEIF v_device_active = 0 THEN
+        SET p_msg = 'inactive device';
+    ELSEIF v_account_active = 0 THEN
+        SET p_msg = 'inactive account';
+    ELSE
+        IF v_squelch <> 0 THEN
+            SET p_msg = 'squelched';
+        ELSE
+            SET p_msg = 'active';
+        END IF;
+        SELECT v_account_id AS account_id, v_squelch AS squelch;
+    END IF;
+END$$
+DELIMITER ;
@@ -0,0 +1,14 @@
+-- Per-device hourly summaries of heartbeats that have aged out of the raw
+-- heartbeats table. Written by the retention job, one row per device-hour.
+CREATE TABLE IF NOT EXISTS heartbeat_hourly (
+    mac_address VARCHAR(17) NOT NULL,
+    hour_start DATETIME NOT NULL,
+    device_id INT NOT NULL,
+    heartbeat_count INT UNSIGNED NOT NULL,
+    first_seen DATETIME(6) NOT NULL,
+    last_seen DATETIME(6) NOT NULL,
+    distinct_reported_ips INT UNSIGNED NOT NULL,
+    distinct_public_ips INT UNSIGNED NOT NULL,
+    PRIMARY KEY (mac_address, hour_start),
+    KEY idx_heartbeat_hourly_hour_start (hour_start)
+) ENGINE = InnoDB;
@@ -0,0 +1,3 @@
+-- Availability reports read rolled-up hours, which need the long poll the
+-- device reported to know how often it was expected to call in.
+ALTER TABLE heartbeat_hourly ADD COLUMN max_lp INT NULL AFTER heartbeat_count;
@@ -0,0 +1,260 @@
+use axum::{
+    Router,
+    extract::{Query, State},
+    http::{StatusCode, header},
+    middleware,
+    response::{IntoResponse, Json, Response},
+    routing::{get, put},
+};
+use log::info;
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::app::{ApiError, api_error, device_cache_len};
+use crate::export::{ExportDataset, ExportParams, ExportService};
+use crate::logging::{self, LoggingStatus};
+use crate::request_id::propagate_request_id;
+use crate::server::AppState;
+
+/// Business logic for the admin endpoints
+pub struct MetricsService;
+
+impl MetricsService {
+    /// Refresh point-in-time gauges, then render every metric
+    pub fn render(state: &AppState) -> String {
+        let metrics = &state.metrics;
+
+        metrics
+            .gauge("device_cache_entries", "Devices in the device cache", &[])
+            .set(device_cache_len() as f64);
+        let lookups = |result| {
+            metrics
+                .counter(
+                    "device_cache_lookups_total",
+                    "Device cache lookups during heartbeat authorization",
+                    &[("result", result)],
+                )
+                .get()
+        };
+        let (hits, misses) = (lookups("hit"), lookups("miss"));
+        let hit_ratio = if hits + misses == 0 {
+            0.0
+        } else {
+            hits as f64 / (hits + misses) as f64
+        };
+        metrics
+            .gauge(
+                "device_cache_hit_ratio",
+                "Share of heartbeat authorizations answered from the device cache",
+                &[],
+            )
+            .set(hit_ratio);
+
+        if let Some(pool) = state.db.pool_stats() {
+            let connections = |state| {
+                metrics.gauge(
+                    "db_pool_connections",
+                    "Database pool connections by state",
+                    &[("state", state)],
+                )
+            };
+            connections("in_use").set(pool.in_use as f64);
+            connections("available").set(pool.available as f64);
+            metrics
+                .gauge(
+                    "db_pool_waiting",
+                    "Database workers waiting for a pool connection",
+                    &[],
+                )
+                .set(pool.waiting as f64);
+            metrics
+                .gauge(
+                    "db_pool_max_connections",
+                    "Maximum database pool connections",
+                    &[],
+                )
+                .set(pool.max_connections as f64);
+        }
+
+        let queue = state.db.stats();
+        metrics
+            .gauge(
+                "db_queue_depth",
+                "Database operations waiting for a worker",
+                &[],
+            )
+            .set(queue.queued as f64);
+        metrics
+            .gauge(
+                "db_in_flight",
+                "Database operations running on a worker",
+                &[],
+            )
+            .set(queue.in_flight as f64);
+
+        let writer = state.writer.stats();
+        metrics
+            .gauge(
+                "heartbeat_writer_queue_depth",
+                "Heartbeats waiting to be persisted",
+                &[],
+            )
+            .set(writer.queued as f64);
+
+        metrics.render()
+    }
+}
+
+/// Body of `PUT /logging/level`
+#[derive(Debug, Deserialize)]
+pub struct LevelChange {
+    /// Log target such as `axum_health_service::app`; the root level when absent
+    pub target: Option<String>,
+    pub level: String,
+    pub revert_after_seconds: Option<u64>,
+}
+
+/// Query of `DELETE /logging/level`
+#[derive(Debug, Deserialize)]
+pub struct LevelTarget {
+    pub target: Option<String>,
+}
+
+/// Body of `PUT /logging/debug-macs`
+#[derive(Debug, Deserialize)]
+pub struct DebugMacsChange {
+    pub macs: Vec<String>,
+    pub revert_after_seconds: Option<u64>,
+}
+
+/// Business logic for changing log levels at runtime
+pub struct LoggingService;
+
+impl LoggingService {
+    pub fn set_level(state: &AppState, change: LevelChange) -> Result<LoggingStatus, ApiError> {
+        let level = logging::parse_level(&change.level)
+            .map_err(|e| api_error(StatusCode::BAD_REQUEST, e.to_string()))?;
+        let target = change.target.filter(|target| !target.is_empty());
+        state
+            .logging
+            .set_level(
+                target,
+                level,
+                change.revert_after_seconds.map(Duration::from_secs),
+            )
+            .map_err(reload_failed)?;
+        Ok(state.logging.status())
+    }
+
+    pub fn reset_level(state: &AppState, target: LevelTarget) -> Result<LoggingStatus, ApiError> {
+        let target = target.target.filter(|target| !target.is_empty());
+        state.logging.reset_level(target).map_err(reload_failed)?;
+        Ok(state.logging.status())
+    }
+
+    pub fn set_debug_macs(
+        state: &AppState,
+        change: DebugMacsChange,
+    ) -> Result<LoggingStatus, ApiError> {
+        let macs: Vec<String> = change
+            .macs
+            .into_iter()
+            .map(|mac| mac.trim().to_string())
+            .filter(|mac| !mac.is_empty())
+            .collect();
+        if macs.is_empty() {
+            return Err(api_error(
+                StatusCode::BAD_REQUEST,
+                "macs must list at least one MAC address",
+            ));
+        }
+        state
+            .logging
+            .set_debug_macs(macs, change.revert_after_seconds.map(Duration::from_secs))
+            .map_err(reload_failed)?;
+        Ok(state.logging.status())
+    }
+
+    pub fn clear_debug_macs(state: &AppState) -> Result<LoggingStatus, ApiError> {
+        state.logging.clear_debug_macs().map_err(reload_failed)?;
+        Ok(state.logging.status())
+    }
+}
+
+fn reload_failed(e: anyhow::Error) -> ApiError {
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("failed to reload logging: {:#}", e),
+    )
+}
+
+async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(
+            header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )],
+        MetricsService::render(&state),
+    )
+}
+
+async fn logging_status(State(state): State<AppState>) -> Json<LoggingStatus> {
+    Json(state.logging.status())
+}
+
+async fn set_log_level(
+    State(state): State<AppState>,
+    Json(change): Json<LevelChange>,
+) -> Result<Json<LoggingStatus>, ApiError> {
+    LoggingService::set_level(&state, change).map(Json)
+}
+
+async fn reset_log_level(
+    State(state): State<AppState>,
+    Query(target): Query<LevelTarget>,
+) -> Result<Json<LoggingStatus>, ApiError> {
+    LoggingService::reset_level(&state, target).map(Json)
+}
+
+async fn set_debug_macs(
+    State(state): State<AppState>,
+    Json(change): Json<DebugMacsChange>,
+) -> Result<Json<LoggingStatus>, ApiError> {
+    LoggingService::set_debug_macs(&state, change).map(Json)
+}
+
+async fn clear_debug_macs(State(state): State<AppState>) -> Result<Json<LoggingStatus>, ApiError> {
+    LoggingService::clear_debug_macs(&state).map(Json)
+}
+
+async fn export_heartbeats(
+    State(state): State<AppState>,
+    Query(params): Query<ExportParams>,
+) -> Result<Response, ApiError> {
+    info!("Heartbeat export requested");
+    ExportService::export(&state, ExportDataset::Heartbeats, params).await
+}
+
+async fn export_devices(
+    State(state): State<AppState>,
+    Query(params): Query<ExportParams>,
+) -> Result<Response, ApiError> {
+    info!("Device export requested");
+    ExportService::export(&state, ExportDataset::Devices, params).await
+}
+
+pub fn create_admin_router(state: AppState) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .route("/logging", get(logging_status))
+        .route("/logging/level", put(set_log_level).delete(reset_log_level))
+        .route(
+            "/logging/debug-macs",
+            put(set_debug_macs).delete(clear_debug_macs),
+        )
+        // Exports read whole tables, so they stay off the public listener
+        .route("/export/heartbeats", get(export_heartbeats))
+        .route("/export/devices", get(export_devices))
+        .layer(middleware::from_fn(propagate_request_id))
+        .with_state(state)
+}
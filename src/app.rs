@@ -1,16 +1,23 @@
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 use lockfreehashmap::LockFreeHashMap;
-use log::{error, info};
-use mysql::prelude::Queryable;
+use log::{debug, error, info};
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use crate::breaker::{CircuitBreakerStatus, CircuitOpenError, CircuitState};
+use crate::config::OpenCircuitPolicy;
+use crate::executor::{DbExecutor, DbExecutorStats};
+use crate::health::{ComponentHealth, HealthStatus, HealthTransition};
+use crate::metrics::RequestTimings;
+use crate::retention::RetentionStatus;
 use crate::server::AppState;
-use axum::{
-    http::StatusCode,
-    response::Json,
-};
+use crate::store::{AuthorizationReason, HeartbeatRecord, PoolStats};
+use crate::webhooks::WebhookStats;
+use crate::writer::WriterStats;
+use axum::{http::StatusCode, response::Json};
 
 // Static lock-free hashmap for caching device data
 #[derive(Clone, Debug, PartialEq)]
@@ -21,15 +28,160 @@ pub struct DeviceCacheEntry {
     pub pip: String,
     pub long_poll: u8,
     pub last_hb_cache_write: Option<DateTime<Utc>>,
+    /// When the database last said the device may report
+    pub authorized_at: DateTime<Utc>,
+}
+
+impl DeviceCacheEntry {
+    /// Entry for a device that has not been persisted since it was authorized
+    fn authorized(mac: String, authorized_at: DateTime<Utc>) -> Self {
+        Self {
+            id: 0,
+            mac,
+            ip: String::new(),
+            pip: String::new(),
+            long_poll: 0,
+            last_hb_cache_write: None,
+            authorized_at,
+        }
+    }
 }
 
 static DEVICE_CACHE: std::sync::LazyLock<LockFreeHashMap<String, DeviceCacheEntry>> =
-    std::sync::LazyLock::new(|| LockFreeHashMap::new());
+    std::sync::LazyLock::new(LockFreeHashMap::new);
+
+/// Number of devices in the device cache
+pub fn device_cache_len() -> usize {
+    DEVICE_CACHE.len()
+}
+
+/// How long to wait before retrying a failed cache warmup
+const CACHE_WARMUP_RETRY_SECS: u64 = 10;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarmupState {
+    #[default]
+    Pending,
+    Running,
+    Ready,
+    Failed,
+}
+
+/// Progress of loading authorized devices into the device cache at startup
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CacheWarmupStatus {
+    pub state: WarmupState,
+    pub devices_loaded: usize,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+    pub last_error: Option<String>,
+}
+
+/// Preloads the device cache so the first heartbeat of each device skips the
+/// database, and so fail-open authorization has something to work with
+#[derive(Clone)]
+pub struct CacheWarmup {
+    status: Arc<Mutex<CacheWarmupStatus>>,
+}
+
+impl CacheWarmup {
+    /// Start warming the cache on the current tokio runtime, retrying until it succeeds
+    pub fn spawn(db: DbExecutor) -> Self {
+        let warmup = Self {
+            status: Arc::new(Mutex::new(CacheWarmupStatus::default())),
+        };
+
+        let task = warmup.clone();
+        tokio::spawn(async move {
+            loop {
+                task.update(|status| {
+                    status.state = WarmupState::Running;
+                    status.started_at = Some(Utc::now().to_rfc3339());
+                });
+                // One query, so warming a large fleet cannot flood the db queue.
+                // Entries expire like any other, after which `is_device_active`
+                // has the final say.
+                let result = db
+                    .run("warm_device_cache", |store| store.list_authorized_devices())
+                    .await;
+
+                match result {
+                    Ok(macs) => {
+                        let authorized = macs.len();
+                        let loaded = Self::load(macs);
+                        info!(
+                            devices = loaded,
+                            already_cached = authorized - loaded;
+                            "Device cache warmed"
+                        );
+                        task.update(|status| {
+                            status.state = WarmupState::Ready;
+                            status.devices_loaded = loaded;
+                            status.finished_at = Some(Utc::now().to_rfc3339());
+                            status.last_error = None;
+                        });
+                        break;
+                    }
+                    Err(e) => {
+                        error!(
+                            retry_in_secs = CACHE_WARMUP_RETRY_SECS,
+                            error:% = e;
+                            "Device cache warmup failed"
+                        );
+                        task.update(|status| {
+                            status.state = WarmupState::Failed;
+                            status.last_error = Some(e.to_string());
+                        });
+                        tokio::time::sleep(Duration::from_secs(CACHE_WARMUP_RETRY_SECS)).await;
+                    }
+                }
+            }
+        });
+
+        warmup
+    }
 
+    pub fn status(&self) -> CacheWarmupStatus {
+        self.status.lock().unwrap().clone()
+    }
 
-struct AuthorizedResult{
+    fn update(&self, f: impl FnOnce(&mut CacheWarmupStatus)) {
+        f(&mut self.status.lock().unwrap());
+    }
+
+    /// Add cache entries for devices that don't have one yet
+    ///
+    /// Entries have no last write, so each device's first heartbeat is still persisted.
+    fn load(macs: Vec<String>) -> usize {
+        let guard = lockfreehashmap::pin();
+        let now = Utc::now();
+        let mut loaded = 0;
+        for mac in macs {
+            if DEVICE_CACHE.get(&mac, &guard).is_some() {
+                continue;
+            }
+            DEVICE_CACHE.insert(mac.clone(), DeviceCacheEntry::authorized(mac, now), &guard);
+            loaded += 1;
+        }
+        loaded
+    }
+}
+
+struct AuthorizedResult {
     authorized: bool,
     squelched: bool,
+    reason: AuthorizationReason,
+    message: Option<String>,
+}
+
+/// Whether a cache entry authorized at `authorized_at` can still be trusted
+fn is_fresh(authorized_at: DateTime<Utc>, now: DateTime<Utc>, ttl: Duration) -> bool {
+    // An entry from the future (clock step) is treated as fresh
+    (now - authorized_at)
+        .to_std()
+        .map(|age| age < ttl)
+        .unwrap_or(true)
 }
 
 #[derive(Deserialize)]
@@ -54,6 +206,60 @@ pub struct HbdResponse {
     pub processed_at: String,
 }
 
+/// Body returned when a heartbeat is refused
+#[derive(Serialize)]
+pub struct HbdRejection {
+    pub status: String,
+    pub reason: AuthorizationReason,
+    pub message: String,
+    pub mac: String,
+    pub processed_at: String,
+}
+
+pub type HbdError = (StatusCode, Json<HbdRejection>);
+
+/// Error body for the query APIs
+#[derive(Serialize)]
+pub struct ErrorResponse {
+    pub status: String,
+    pub message: String,
+}
+
+pub type ApiError = (StatusCode, Json<ErrorResponse>);
+
+pub fn api_error(status: StatusCode, message: impl Into<String>) -> ApiError {
+    (
+        status,
+        Json(ErrorResponse {
+            status: "error".to_string(),
+            message: message.into(),
+        }),
+    )
+}
+
+/// Map a failed store call to a 503
+pub fn store_error(e: anyhow::Error) -> ApiError {
+    // The cause is logged only; driver errors can describe the schema and server
+    error!(error:% = e; "Store query failed");
+    api_error(StatusCode::SERVICE_UNAVAILABLE, "database unavailable")
+}
+
+/// Parse an RFC 3339 timestamp or Unix seconds
+pub fn parse_time(name: &str, value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(secs) = value.parse::<i64>() {
+        return DateTime::from_timestamp(secs, 0)
+            .ok_or_else(|| format!("{} is out of range", name));
+    }
+    DateTime::parse_from_rfc3339(value)
+        .map(|dt| dt.with_timezone(&Utc))
+        .map_err(|e| format!("{} must be RFC 3339 or Unix seconds: {}", name, e))
+}
+
+/// Parse a time query parameter, rejecting bad values with 400
+pub fn parse_time_param(name: &str, value: &str) -> Result<DateTime<Utc>, ApiError> {
+    parse_time(name, value).map_err(|message| api_error(StatusCode::BAD_REQUEST, message))
+}
+
 #[derive(Serialize)]
 pub struct HbdData {
     pub id: i32,
@@ -66,7 +272,7 @@ pub struct HbdData {
 
 #[derive(Serialize)]
 pub struct HealthResponse {
-    pub status: String,
+    pub status: HealthStatus,
     pub timestamp: String,
     pub service_name: String,
     pub version: String,
@@ -74,13 +280,43 @@ pub struct HealthResponse {
     pub user_agent: Option<String>,
     pub headers_count: usize,
     pub database_status: String,
+    /// When the component checks last ran, and how long ago
+    pub checked_at: String,
+    pub check_age_ms: u64,
+    /// Result of every registered health check
+    pub components: Vec<ComponentHealth>,
+    /// Recent component status changes, oldest first
+    pub recent_transitions: Vec<HealthTransition>,
+    pub database_pool: Option<PoolStats>,
+    pub database_queue: DbExecutorStats,
+    pub circuit_breaker: CircuitBreakerStatus,
+    pub heartbeat_writer: WriterStats,
+    pub retention: RetentionStatus,
+    pub webhooks: WebhookStats,
+    pub cache_warmup: CacheWarmupStatus,
+}
+
+#[derive(Deserialize)]
+pub struct HealthParams {
+    /// Run the component checks now instead of serving the last result
+    pub refresh: Option<bool>,
 }
 
+/// `/livez` body; only says the process is up and serving
 #[derive(Serialize)]
-pub struct DatabaseHealth {
-    pub is_connected: bool,
-    pub connection_test_duration_ms: Option<u64>,
-    pub error_message: Option<String>,
+pub struct LivenessResponse {
+    pub status: String,
+    pub timestamp: String,
+}
+
+/// `/readyz` body, one entry per health check
+#[derive(Serialize)]
+pub struct ReadinessResponse {
+    pub status: String,
+    pub timestamp: String,
+    pub checked_at: String,
+    pub check_age_ms: u64,
+    pub components: Vec<ComponentHealth>,
 }
 
 /// Business logic for health check endpoint
@@ -88,34 +324,37 @@ pub struct HealthService;
 
 impl HealthService {
     /// Process health check request and return response
-    pub fn process_health_check(
+    pub async fn process_health_check(
         state: &AppState,
         client_addr: SocketAddr,
         headers_count: usize,
         user_agent: Option<String>,
+        refresh: bool,
     ) -> HealthResponse {
-        info!("Processing health check for client: {}", client_addr);
+        info!(client:% = client_addr; "Processing health check");
 
         // Increment health counter
-        let current_count = state.health_count.fetch_add(1) + 1;
+        let current_count = state.health_count.inc();
 
-        // Check database health
-        let db_health = Self::check_database_health(state);
-
-        let overall_status = if db_health.is_connected {
-            "healthy".to_string()
+        let snapshot = if refresh {
+            info!(client:% = client_addr; "Health check refresh requested");
+            state.health.refresh_requested().await
         } else {
-            "degraded".to_string()
+            state.health.latest().await
         };
 
-        let database_status = if db_health.is_connected {
+        let db_connected = snapshot
+            .components
+            .iter()
+            .any(|c| c.name == "database" && c.status != HealthStatus::Unhealthy);
+        let database_status = if db_connected {
             "connected".to_string()
         } else {
             "disconnected".to_string()
         };
 
         let response = HealthResponse {
-            status: overall_status,
+            status: snapshot.status,
             timestamp: Utc::now().to_rfc3339(),
             service_name: state.service_name.clone(),
             version: state.version.clone(),
@@ -123,49 +362,89 @@ impl HealthService {
             user_agent,
             headers_count,
             database_status,
+            checked_at: snapshot.checked_at.to_rfc3339(),
+            check_age_ms: snapshot.age_ms(),
+            components: snapshot.components,
+            recent_transitions: state.health.transitions(),
+            database_pool: state.db.pool_stats(),
+            database_queue: state.db.stats(),
+            circuit_breaker: state.db.breaker().status(),
+            heartbeat_writer: state.writer.stats(),
+            retention: state.retention.status(),
+            webhooks: state.webhooks.stats(),
+            cache_warmup: state.cache.status(),
         };
 
         info!(
-            "Health check completed for client {}: status={}, db_connected={}, count={}",
-            client_addr, response.status, db_health.is_connected, current_count
+            client:% = client_addr,
+            status = response.status.as_str(),
+            db_connected = db_connected,
+            count = current_count;
+            "Health check completed"
         );
 
         response
     }
 
-    /// Check database connectivity and performance
-    pub fn check_database_health(state: &AppState) -> DatabaseHealth {
-        let start_time = std::time::Instant::now();
+    /// One-line `text/plain` form of a health response, status first so
+    /// simple monitors can match on the start of the body
+    pub fn text_line(response: &HealthResponse) -> String {
+        let mut line = format!(
+            "{} service={} version={}",
+            response.status.as_str(),
+            response.service_name,
+            response.version
+        );
+        for component in &response.components {
+            line.push_str(&format!(
+                " {}={}",
+                component.name,
+                component.status.as_str()
+            ));
+        }
+        line.push_str(&format!(" age_ms={}\n", response.check_age_ms));
+        line
+    }
 
-        match state.get_connection() {
-            Ok(mut conn) => match conn.query_drop("SELECT 1") {
-                Ok(_) => {
-                    let duration = start_time.elapsed();
-                    DatabaseHealth {
-                        is_connected: true,
-                        connection_test_duration_ms: Some(duration.as_millis() as u64),
-                        error_message: None,
-                    }
-                }
-                Err(e) => {
-                    error!("Database health check query failed: {}", e);
-                    DatabaseHealth {
-                        is_connected: false,
-                        connection_test_duration_ms: None,
-                        error_message: Some(format!("Query failed: {}", e)),
-                    }
-                }
-            },
-            Err(e) => {
-                error!("Failed to get database connection for health check: {}", e);
-                DatabaseHealth {
-                    is_connected: false,
-                    connection_test_duration_ms: None,
-                    error_message: Some(format!("Connection failed: {}", e)),
-                }
-            }
+    /// Status code only, from the last background check; not counted in `health_count`
+    pub async fn head_status(state: &AppState) -> StatusCode {
+        let snapshot = state.health.latest().await;
+        state.health.status_code(snapshot.status)
+    }
+
+    /// Liveness never touches the database or the health counter
+    pub fn liveness() -> LivenessResponse {
+        LivenessResponse {
+            status: "alive".to_string(),
+            timestamp: Utc::now().to_rfc3339(),
         }
     }
+
+    /// Ready unless a critical health check was unhealthy on the last run
+    pub async fn readiness(state: &AppState) -> (bool, ReadinessResponse) {
+        let snapshot = state.health.latest().await;
+        let ready = snapshot.components.iter().all(|c| c.ready);
+        if !ready {
+            let problems: Vec<String> = snapshot
+                .components
+                .iter()
+                .filter(|c| !c.ready)
+                .map(|c| format!("{}: {}", c.name, c.error.as_deref().unwrap_or("not ready")))
+                .collect();
+            info!(problems = problems.join("; "); "Readiness check failed");
+        }
+
+        (
+            ready,
+            ReadinessResponse {
+                status: if ready { "ready" } else { "not_ready" }.to_string(),
+                timestamp: Utc::now().to_rfc3339(),
+                checked_at: snapshot.checked_at.to_rfc3339(),
+                check_age_ms: snapshot.age_ms(),
+                components: snapshot.components,
+            },
+        )
+    }
 }
 
 /// Business logic for heartbeat (HBD) endpoint
@@ -173,28 +452,112 @@ pub struct HbdService;
 
 impl HbdService {
     /// Process heartbeat data and return response
-    pub fn process_heartbeat(
+    pub async fn process_heartbeat(
         state: &AppState,
         params: HbdParams,
         client_addr: SocketAddr,
-    ) -> Result<Json<crate::app::HbdResponse>, StatusCode> {
+        timings: &RequestTimings,
+    ) -> Result<Json<crate::app::HbdResponse>, HbdError> {
         info!(
-            "Processing HBD for client {}: ID={}, MAC={}, IP={}",
-            client_addr, params.id, params.mac, params.ip
+            client:% = client_addr,
+            device_id = params.id,
+            mac = params.mac.as_str(),
+            ip = params.ip.as_str();
+            "Processing HBD"
         );
 
         // Convert timestamp to ISO format if provided
         let timestamp_iso = Self::convert_timestamp_to_iso(params.ts);
+        debug!(
+            mac = params.mac.as_str(),
+            lp = params.lp,
+            ts = params.ts,
+            device_time = timestamp_iso.as_deref();
+            "Heartbeat received"
+        );
 
         // Increment HBD counter
-        let current_count = state.hbd_count.fetch_add(1) + 1;
+        let current_count = state.hbd_count.inc();
 
-        // Here you could add database persistence logic
-        // Self::persist_heartbeat_data(&state, &params)?;
+        let authorization = match Self::get_authorized(state, &params.mac, timings).await {
+            Ok(authorization) => authorization,
+            Err(rejection) => {
+                Self::count_outcome(state, "unavailable");
+                return Err(rejection);
+            }
+        };
+        if !authorization.authorized {
+            Self::count_outcome(state, "rejected");
+            let message = authorization
+                .message
+                .unwrap_or_else(|| authorization.reason.description().to_string());
+            info!(
+                client:% = client_addr,
+                mac = params.mac.as_str(),
+                reason:serde = authorization.reason,
+                detail = message.as_str();
+                "HBD rejected: device is not authorized"
+            );
+            state
+                .webhooks
+                .device_rejected(&params.mac, authorization.reason, &message);
+            return Err(Self::reject(
+                StatusCode::FORBIDDEN,
+                &params.mac,
+                authorization.reason,
+                message,
+            ));
+        }
+
+        state.webhooks.heartbeat_seen(
+            &params.mac,
+            &params.ip,
+            &client_addr.ip().to_string(),
+            params.lp,
+        );
+
+        debug!(
+            mac = params.mac.as_str(),
+            squelched = authorization.squelched,
+            reason:serde = authorization.reason;
+            "Device authorized"
+        );
+        let (status, message) = if authorization.squelched {
+            info!(mac = params.mac.as_str(); "HBD is squelched, skipping persistence");
+            Self::count_outcome(state, "squelched");
+            ("squelched", "Heartbeat received; device is squelched")
+        } else {
+            let started = Instant::now();
+            let queued = Self::persist_heartbeat_data(state, &params, client_addr);
+            // The INSERT happens on the writer, after the response is sent
+            timings.record("enqueue", started.elapsed());
+            match queued {
+                Ok(()) => {
+                    Self::count_outcome(state, "accepted");
+                    ("success", "Heartbeat data received and processed")
+                }
+                Err(e) => {
+                    error!(mac = params.mac.as_str(), error:% = e; "Failed to persist heartbeat");
+                    Self::count_outcome(state, "not_persisted");
+                    state
+                        .metrics
+                        .counter(
+                            "heartbeats_dropped_total",
+                            "Accepted heartbeats the writer could not take",
+                            &[],
+                        )
+                        .inc();
+                    (
+                        "accepted_not_persisted",
+                        "Heartbeat received but not stored; the server is overloaded",
+                    )
+                }
+            }
+        };
 
         let response = HbdResponse {
-            status: "success".to_string(),
-            message: "Heartbeat data received and processed".to_string(),
+            status: status.to_string(),
+            message: message.to_string(),
             received_data: HbdData {
                 id: params.id,
                 mac: params.mac,
@@ -207,72 +570,207 @@ impl HbdService {
         };
 
         info!(
-            "HBD processed successfully for client {}: count={}",
-            client_addr, current_count
+            client:% = client_addr,
+            mac = response.received_data.mac.as_str(),
+            status = status,
+            count = current_count;
+            "HBD processed successfully"
         );
 
         Ok(Json(response))
     }
 
+    fn count_outcome(state: &AppState, outcome: &str) {
+        state
+            .metrics
+            .counter(
+                "hbd_outcomes_total",
+                "Heartbeats by outcome: accepted, not_persisted, squelched, rejected, or unavailable",
+                &[("outcome", outcome)],
+            )
+            .inc();
+    }
+
     /// is mac in cache or db
-    fn get_authorized(
-        &self, 
+    async fn get_authorized(
         state: &AppState,
         mac: &str,
-    ) -> Result<AuthorizedResult, StatusCode> {
-         let guard = lockfreehashmap::pin();
-         match DEVICE_CACHE.get(mac, &guard) {
-            None => {
-                //call db to get auth and squelched.
-                self.call_is_device_active(state, mac)
+        timings: &RequestTimings,
+    ) -> Result<AuthorizedResult, HbdError> {
+        let breaker = state.db.breaker();
+        if breaker.policy() == OpenCircuitPolicy::FailClosed
+            && breaker.state() == CircuitState::Open
+        {
+            info!(mac = mac; "Rejecting HBD: database circuit is open and policy is fail-closed");
+            return Err(Self::reject(
+                StatusCode::SERVICE_UNAVAILABLE,
+                mac,
+                AuthorizationReason::DbError,
+                "database unavailable (circuit open)".to_string(),
+            ));
+        }
+
+        // The epoch guard is not Send, so it must be released before awaiting the db
+        let started = Instant::now();
+        let authorized_at = {
+            let guard = lockfreehashmap::pin();
+            DEVICE_CACHE
+                .get(mac, &guard)
+                .map(|entry| entry.authorized_at)
+        };
+        let expired =
+            authorized_at.is_some_and(|at| !is_fresh(at, Utc::now(), state.device_cache_ttl));
+        let cached = authorized_at.is_some() && !expired;
+        timings.record("cache", started.elapsed());
+        debug!(mac = mac, cached = cached, expired = expired; "Device cache lookup");
+        if cached {
+            Self::count_lookup(state, "hit");
+            return Ok(Self::cache_hit());
+        }
+
+        // Fail-open keeps accepting cached devices whatever their age, or an
+        // outage longer than the cache TTL would reject every device
+        let fail_open = expired && breaker.policy() == OpenCircuitPolicy::FailOpen;
+        if fail_open && breaker.state() != CircuitState::Closed {
+            return Ok(Self::stale_hit(state, mac));
+        }
+
+        Self::count_lookup(state, "miss");
+        //call db to get auth and squelched.
+        let started = Instant::now();
+        let result = Self::call_is_device_active(state, mac).await;
+        timings.record("db_auth", started.elapsed());
+        match result {
+            Err((_, Json(rejection)))
+                if fail_open && rejection.reason == AuthorizationReason::DbError =>
+            {
+                Ok(Self::stale_hit(state, mac))
             }
-            Some(_) => Ok(AuthorizedResult {
-                authorized: true,
-                squelched: false,
-            }),
+            result => result,
         }
     }
 
-    fn call_is_device_active(
-        &self,
-        state: &AppState,
-        mac: &str,
-    ) -> Result<AuthorizedResult, StatusCode> {
-        // Call the stored procedure
-        match state.get_connection() {
-            Ok(mut conn) => {
-                let result: Result<Vec<mysql::Row>, mysql::Error> =
-                    conn.exec("CALL is_device_active(?, @msg)", (mac,));
+    fn count_lookup(state: &AppState, result: &str) {
+        state
+            .metrics
+            .counter(
+                "device_cache_lookups_total",
+                "Device cache lookups during heartbeat authorization",
+                &[("result", result)],
+            )
+            .inc();
+    }
 
-                // Handle the @msg output parameter properly
-                let _message: Result<Option<String>, mysql::Error> =
-                    conn.query_first("SELECT @msg");
+    fn cache_hit() -> AuthorizedResult {
+        AuthorizedResult {
+            authorized: true,
+            squelched: false,
+            reason: AuthorizationReason::Active,
+            message: None,
+        }
+    }
 
-                match result {
-                    Ok(mut rows) => {
-                        if let Some(row) = rows.pop() {
-                            let (_account_id, squelch): (Option<i32>, i32) = mysql::from_row(row);
-                            Ok(AuthorizedResult {
-                                authorized: true,
-                                squelched: squelch != 0,
-                            })
-                        } else {
-                            Ok(AuthorizedResult {
-                                authorized: false,
-                                squelched: true,
-                            })
-                        }
-                    }
-                    Err(_) => Ok(AuthorizedResult {
-                        authorized: false,
-                        squelched: true,
-                    }),
+    /// Accept a device whose cache entry expired because the database cannot be asked
+    fn stale_hit(state: &AppState, mac: &str) -> AuthorizedResult {
+        info!(mac = mac; "Accepting HBD from expired cache entry: database unavailable and policy is fail-open");
+        Self::count_lookup(state, "stale");
+        Self::cache_hit()
+    }
+
+    async fn call_is_device_active(
+        state: &AppState,
+        mac: &str,
+    ) -> Result<AuthorizedResult, HbdError> {
+        debug!(mac = mac; "Calling is_device_active");
+        let lookup_mac = mac.to_string();
+        match state
+            .db
+            .run("authorize_device", move |store| {
+                store.authorize_device(&lookup_mac)
+            })
+            .await
+        {
+            Ok(authorization) => {
+                info!(
+                    mac = mac,
+                    reason:serde = authorization.reason,
+                    detail = authorization.message.as_deref();
+                    "is_device_active result"
+                );
+                // Only devices that may report unsquelched are cached, so a
+                // device the database no longer accepts is dropped right away
+                if authorization.authorized && !authorization.squelched {
+                    Self::cache_authorized(mac);
+                } else {
+                    Self::cache_forget(mac);
                 }
+                Ok(AuthorizedResult {
+                    authorized: authorization.authorized,
+                    squelched: authorization.squelched,
+                    reason: authorization.reason,
+                    message: authorization.message,
+                })
+            }
+            Err(e) if e.is::<CircuitOpenError>() => {
+                info!(mac = mac; "Cannot authorize uncached device: database circuit is open");
+                Err(Self::reject(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    mac,
+                    AuthorizationReason::DbError,
+                    "database unavailable (circuit open)".to_string(),
+                ))
+            }
+            Err(e) => {
+                error!(mac = mac, error:% = e; "Device authorization lookup failed");
+                Err(Self::reject(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    mac,
+                    AuthorizationReason::DbError,
+                    "database unavailable".to_string(),
+                ))
             }
-            Err(_) => Err(StatusCode::SERVICE_UNAVAILABLE),
         }
     }
 
+    /// Build the error response for a refused heartbeat
+    fn reject(
+        status: StatusCode,
+        mac: &str,
+        reason: AuthorizationReason,
+        message: String,
+    ) -> HbdError {
+        (
+            status,
+            Json(HbdRejection {
+                status: "rejected".to_string(),
+                reason,
+                message,
+                mac: mac.to_string(),
+                processed_at: Utc::now().to_rfc3339(),
+            }),
+        )
+    }
+
+    /// Start a new cache TTL for `mac`, keeping what is known about its last write
+    fn cache_authorized(mac: &str) {
+        let now = Utc::now();
+        let guard = lockfreehashmap::pin();
+        let entry = match DEVICE_CACHE.get(mac, &guard) {
+            Some(entry) => DeviceCacheEntry {
+                authorized_at: now,
+                ..entry.clone()
+            },
+            None => DeviceCacheEntry::authorized(mac.to_string(), now),
+        };
+        DEVICE_CACHE.insert(mac.to_string(), entry, &guard);
+    }
+
+    fn cache_forget(mac: &str) {
+        let guard = lockfreehashmap::pin();
+        if DEVICE_CACHE.remove(mac, &guard).is_some() {
+            info!(mac = mac; "Device removed from the device cache");
+        }
+    }
 
     /// Get Last database presist for this cache entry.
     fn get_last_heartbeat_write(mac: &str) -> Option<DateTime<Utc>> {
@@ -283,33 +781,338 @@ impl HbdService {
         }
     }
 
-
     /// Convert Unix timestamp to ISO format
-    fn convert_timestamp_to_iso(timestamp: Option<i64>) ->Option<String> {
+    fn convert_timestamp_to_iso(timestamp: Option<i64>) -> Option<String> {
         match timestamp {
-            Some(ts) => match DateTime::from_timestamp(ts, 0) {
-                Some(dt) => Some(dt.to_rfc3339()),
-                None => None
-            },
+            Some(ts) => DateTime::from_timestamp(ts, 0).map(|dt| dt.to_rfc3339()),
             None => None,
         }
     }
 
-    /// Persist heartbeat data to database (placeholder for future implementation)
-    #[allow(dead_code)]
-    fn persist_heartbeat_data(_state: &AppState, params: &HbdParams) -> Result<()> {
-        // This is where you would implement database persistence
-        // Example:
-        // let mut conn = state.get_connection()?;
-        // conn.exec_drop(
-        //     "INSERT INTO heartbeats (device_id, mac_address, ip_address, last_ping, timestamp) VALUES (?, ?, ?, ?, ?)",
-        //     (params.id, &params.mac, &params.ip, params.lp, params.ts)
-        // )?;
+    /// Queue heartbeat data for persistence, at most once per long poll interval
+    fn persist_heartbeat_data(
+        state: &AppState,
+        params: &HbdParams,
+        client_addr: SocketAddr,
+    ) -> Result<()> {
+        let now = Utc::now();
+        let long_poll = params.lp.unwrap_or(0).clamp(0, u8::MAX as i32) as u8;
+
+        if let Some(last_write) = Self::get_last_heartbeat_write(&params.mac)
+            && (now - last_write).num_seconds() < long_poll as i64
+        {
+            info!(
+                device_id = params.id,
+                last_write:% = last_write;
+                "Heartbeat already persisted this long poll interval, skipping"
+            );
+            return Ok(());
+        }
 
-        info!(
-            "Heartbeat data would be persisted for device ID: {}",
-            params.id
+        debug!(
+            device_id = params.id,
+            mac = params.mac.as_str(),
+            long_poll = long_poll;
+            "Queueing heartbeat for persistence"
         );
+        let record = HeartbeatRecord {
+            device_id: params.id,
+            mac: params.mac.clone(),
+            reported_ip: params.ip.clone(),
+            public_ip: client_addr.ip().to_string(),
+            lp: params.lp,
+            device_ts: params.ts,
+            received_at: now,
+        };
+        let public_ip = record.public_ip.clone();
+        state.writer.enqueue(record)?;
+
+        let guard = lockfreehashmap::pin();
+        // Persisting does not re-authorize the device, so its TTL carries over
+        let authorized_at = DEVICE_CACHE
+            .get(&params.mac, &guard)
+            .map(|entry| entry.authorized_at)
+            .unwrap_or(now);
+        DEVICE_CACHE.insert(
+            params.mac.clone(),
+            DeviceCacheEntry {
+                id: params.id as u64,
+                mac: params.mac.clone(),
+                ip: params.ip.clone(),
+                pip: public_ip,
+                long_poll,
+                last_hb_cache_write: Some(now),
+                authorized_at,
+            },
+            &guard,
+        );
+
+        info!(device_id = params.id, mac = params.mac.as_str(); "Heartbeat data queued");
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breaker::CircuitBreaker;
+    use crate::config::{
+        CircuitBreakerConfig, Config, HealthConfig, RetentionConfig, WebhooksConfig,
+    };
+    use crate::health::{HealthMonitor, HealthRegistry};
+    use crate::logging::{self, LogControl};
+    use crate::metrics::MetricsRegistry;
+    use crate::retention::RetentionJob;
+    use crate::server::AppCore;
+    use crate::store::{DeviceStore, InMemoryDeviceStore};
+    use crate::webhooks::WebhookDispatcher;
+    use crate::writer::HeartbeatWriter;
+    use std::sync::OnceLock;
+
+    /// log4rs can only be installed once per process
+    fn log_control() -> LogControl {
+        static CONTROL: OnceLock<LogControl> = OnceLock::new();
+        CONTROL
+            .get_or_init(|| {
+                let mut config = Config::default();
+                config.app.log_level = "off".to_string();
+                config.logging.console = false;
+                logging::init(&config, true).unwrap()
+            })
+            .clone()
+    }
+
+    fn test_state(store: Arc<InMemoryDeviceStore>, device_cache_ttl: Duration) -> AppState {
+        test_state_with_breaker(store, device_cache_ttl, CircuitBreakerConfig::default())
+    }
+
+    fn test_state_with_breaker(
+        store: Arc<InMemoryDeviceStore>,
+        device_cache_ttl: Duration,
+        breaker: CircuitBreakerConfig,
+    ) -> AppState {
+        AppState::new(test_core(store, device_cache_ttl, breaker))
+    }
+
+    fn test_core(
+        store: Arc<InMemoryDeviceStore>,
+        device_cache_ttl: Duration,
+        breaker: CircuitBreakerConfig,
+    ) -> AppCore {
+        let metrics = Arc::new(MetricsRegistry::new());
+        let db = DbExecutor::new(store, 1, 16, CircuitBreaker::new(breaker), metrics.clone());
+        let retention = RetentionConfig {
+            enabled: false,
+            ..Default::default()
+        };
+        AppCore {
+            service_name: "test".to_string(),
+            version: "0.0.0".to_string(),
+            health_count: metrics.counter("health_requests_total", "Requests to /health", &[]),
+            hbd_count: metrics.counter("hbd_requests_total", "Requests to /hbd", &[]),
+            slow_request_threshold: Duration::from_secs(1),
+            device_cache_ttl,
+            exports: Arc::new(tokio::sync::Semaphore::new(1)),
+            metrics,
+            writer: HeartbeatWriter::spawn(db.clone(), 16),
+            retention: RetentionJob::spawn(db.clone(), retention),
+            webhooks: WebhookDispatcher::spawn(WebhooksConfig::default()),
+            cache: CacheWarmup::spawn(db.clone()),
+            health: HealthMonitor::spawn(
+                HealthRegistry::new(Duration::from_secs(1)),
+                &HealthConfig::default(),
+            ),
+            db,
+            logging: log_control(),
+        }
+    }
+
+    fn params(mac: &str) -> HbdParams {
+        HbdParams {
+            id: 7,
+            mac: mac.to_string(),
+            ip: "10.0.0.7".to_string(),
+            lp: Some(30),
+            ts: None,
+        }
+    }
+
+    async fn heartbeat(state: &AppState, mac: &str) -> Result<HbdResponse, StatusCode> {
+        let client: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+        HbdService::process_heartbeat(state, params(mac), client, &RequestTimings::default())
+            .await
+            .map(|Json(response)| response)
+            .map_err(|(status, _)| status)
+    }
+
+    /// The writer persists in the background, so give it a moment
+    async fn persisted(store: &InMemoryDeviceStore, mac: &str) -> usize {
+        for _ in 0..50 {
+            let count = store.latest_heartbeats(mac, 10).unwrap().len();
+            if count > 0 {
+                return count;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        0
+    }
+
+    // The device cache is process wide, so every test uses its own MACs
+
+    #[tokio::test]
+    async fn unknown_device_is_rejected() {
+        let store = Arc::new(InMemoryDeviceStore::new());
+        let state = test_state(store.clone(), Duration::from_secs(300));
+
+        let result = heartbeat(&state, "00:00:00:00:26:01").await;
+
+        assert_eq!(result.err(), Some(StatusCode::FORBIDDEN));
+        assert_eq!(persisted(&store, "00:00:00:00:26:01").await, 0);
+    }
+
+    #[tokio::test]
+    async fn active_device_is_persisted() {
+        let store = Arc::new(InMemoryDeviceStore::new());
+        store.register_device("00:00:00:00:26:02", Some(1), false);
+        let state = test_state(store.clone(), Duration::from_secs(300));
+
+        let response = heartbeat(&state, "00:00:00:00:26:02").await.unwrap();
+
+        assert_eq!(response.status, "success");
+        assert_eq!(persisted(&store, "00:00:00:00:26:02").await, 1);
+    }
+
+    #[tokio::test]
+    async fn squelched_device_is_not_persisted() {
+        let store = Arc::new(InMemoryDeviceStore::new());
+        store.register_device("00:00:00:00:26:03", Some(1), true);
+        let state = test_state(store.clone(), Duration::from_secs(300));
+
+        let response = heartbeat(&state, "00:00:00:00:26:03").await.unwrap();
+
+        assert_eq!(response.status, "squelched");
+        assert_eq!(persisted(&store, "00:00:00:00:26:03").await, 0);
+    }
+
+    #[tokio::test]
+    async fn deactivated_device_is_served_from_cache_until_ttl() {
+        let store = Arc::new(InMemoryDeviceStore::new());
+        store.register_device("00:00:00:00:26:04", Some(1), false);
+        let state = test_state(store.clone(), Duration::from_secs(300));
+
+        heartbeat(&state, "00:00:00:00:26:04").await.unwrap();
+        store.deactivate_device("00:00:00:00:26:04");
+
+        assert!(heartbeat(&state, "00:00:00:00:26:04").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn deactivated_device_is_rejected_once_cache_expires() {
+        let store = Arc::new(InMemoryDeviceStore::new());
+        store.register_device("00:00:00:00:26:05", Some(1), false);
+        let state = test_state(store.clone(), Duration::ZERO);
+
+        heartbeat(&state, "00:00:00:00:26:05").await.unwrap();
+        store.deactivate_device("00:00:00:00:26:05");
+
+        let result = heartbeat(&state, "00:00:00:00:26:05").await;
+        assert_eq!(result.err(), Some(StatusCode::FORBIDDEN));
+        let guard = lockfreehashmap::pin();
+        assert!(DEVICE_CACHE.get("00:00:00:00:26:05", &guard).is_none());
+    }
+
+    #[tokio::test]
+    async fn lookup_failure_is_unavailable_not_rejected() {
+        let store = Arc::new(InMemoryDeviceStore::new());
+        store.register_device("00:00:00:00:31:01", Some(1), false);
+        store.set_unavailable(true);
+        let state = test_state(store.clone(), Duration::from_secs(300));
+
+        let client: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+        let (status, Json(rejection)) = HbdService::process_heartbeat(
+            &state,
+            params("00:00:00:00:31:01"),
+            client,
+            &RequestTimings::default(),
+        )
+        .await
+        .err()
+        .unwrap();
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(rejection.reason, AuthorizationReason::DbError);
+        assert_eq!(rejection.message, "database unavailable");
+        let outcomes = |outcome| {
+            state
+                .metrics
+                .counter("hbd_outcomes_total", "", &[("outcome", outcome)])
+                .get()
+        };
+        assert_eq!(outcomes("unavailable"), 1);
+        assert_eq!(outcomes("rejected"), 0);
+    }
+
+    /// A cached device whose entry expired while the database is unavailable
+    async fn expired_device_during_outage(
+        mac: &str,
+        open_policy: OpenCircuitPolicy,
+    ) -> Vec<Result<HbdResponse, StatusCode>> {
+        let store = Arc::new(InMemoryDeviceStore::new());
+        store.register_device(mac, Some(1), false);
+        let breaker = CircuitBreakerConfig {
+            failure_threshold: 1,
+            open_seconds: 300,
+            open_policy,
+            ..Default::default()
+        };
+        let state = test_state_with_breaker(store.clone(), Duration::ZERO, breaker);
+        heartbeat(&state, mac).await.unwrap();
+        store.set_unavailable(true);
+
+        // The first lookup fails and trips the breaker, the second finds it open
+        let failed_lookup = heartbeat(&state, mac).await;
+        assert_eq!(state.db.breaker().state(), CircuitState::Open);
+        let open_circuit = heartbeat(&state, mac).await;
+        vec![failed_lookup, open_circuit]
+    }
+
+    #[tokio::test]
+    async fn fail_open_accepts_expired_cache_entries() {
+        let results =
+            expired_device_during_outage("00:00:00:00:28:01", OpenCircuitPolicy::FailOpen).await;
+
+        for result in results {
+            assert_eq!(result.unwrap().status, "success");
+        }
+    }
+
+    #[tokio::test]
+    async fn fail_closed_rejects_expired_cache_entries() {
+        let results =
+            expired_device_during_outage("00:00:00:00:28:02", OpenCircuitPolicy::FailClosed).await;
+
+        for result in results {
+            assert_eq!(result.err(), Some(StatusCode::SERVICE_UNAVAILABLE));
+        }
+    }
+
+    #[tokio::test]
+    async fn heartbeat_the_writer_cannot_take_is_reported() {
+        let store = Arc::new(InMemoryDeviceStore::new());
+        store.register_device("00:00:00:00:27:01", Some(1), false);
+        let state = AppState::new(AppCore {
+            writer: HeartbeatWriter::stopped(),
+            ..test_core(
+                store.clone(),
+                Duration::from_secs(300),
+                CircuitBreakerConfig::default(),
+            )
+        });
+
+        let response = heartbeat(&state, "00:00:00:00:27:01").await.unwrap();
+
+        assert_eq!(response.status, "accepted_not_persisted");
+        let dropped = state.metrics.counter("heartbeats_dropped_total", "", &[]);
+        assert_eq!(dropped.get(), 1);
+    }
+}
@@ -0,0 +1,192 @@
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::{CircuitBreakerConfig, OpenCircuitPolicy};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// Snapshot of the breaker reported by `/health`
+#[derive(Clone, Debug, Serialize)]
+pub struct CircuitBreakerStatus {
+    pub state: CircuitState,
+    pub policy: OpenCircuitPolicy,
+    pub consecutive_failures: u32,
+    pub failure_threshold: u32,
+    pub rejected_calls: u64,
+    pub last_state_change: String,
+    pub retry_in_seconds: Option<u64>,
+}
+
+/// Error returned instead of calling the store while the circuit is open
+#[derive(Debug)]
+pub struct CircuitOpenError;
+
+impl std::fmt::Display for CircuitOpenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Database circuit breaker is open")
+    }
+}
+
+impl std::error::Error for CircuitOpenError {}
+
+struct BreakerInner {
+    state: CircuitState,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    half_open_in_flight: u32,
+    rejected_calls: u64,
+    last_state_change: DateTime<Utc>,
+}
+
+/// Closed/open/half-open circuit breaker guarding database calls
+///
+/// After `failure_threshold` consecutive failures the circuit opens and calls
+/// are rejected without touching the database. Once `open_seconds` have passed
+/// up to `half_open_max_calls` trial calls are let through; a success closes
+/// the circuit again and a failure re-opens it.
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    inner: Mutex<BreakerInner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            inner: Mutex::new(BreakerInner {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                opened_at: None,
+                half_open_in_flight: 0,
+                rejected_calls: 0,
+                last_state_change: Utc::now(),
+            }),
+        }
+    }
+
+    pub fn policy(&self) -> OpenCircuitPolicy {
+        self.config.open_policy
+    }
+
+    /// Current state, moving from open to half-open if the cool-down elapsed
+    pub fn state(&self) -> CircuitState {
+        let mut inner = self.inner.lock().unwrap();
+        self.refresh(&mut inner);
+        inner.state
+    }
+
+    /// Ask permission to call the database
+    pub fn try_acquire(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        self.refresh(&mut inner);
+
+        match inner.state {
+            CircuitState::Closed => true,
+            CircuitState::HalfOpen
+                if inner.half_open_in_flight < self.config.half_open_max_calls =>
+            {
+                inner.half_open_in_flight += 1;
+                true
+            }
+            _ => {
+                inner.rejected_calls += 1;
+                false
+            }
+        }
+    }
+
+    /// Give back a permit for a call that never reached the database
+    pub fn release(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state == CircuitState::HalfOpen {
+            inner.half_open_in_flight = inner.half_open_in_flight.saturating_sub(1);
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures = 0;
+        if inner.state == CircuitState::HalfOpen {
+            info!("Database circuit breaker closed after successful trial call");
+            Self::transition(&mut inner, CircuitState::Closed);
+        }
+    }
+
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures += 1;
+
+        match inner.state {
+            CircuitState::HalfOpen => {
+                warn!("Database circuit breaker trial call failed, re-opening");
+                Self::transition(&mut inner, CircuitState::Open);
+            }
+            CircuitState::Closed if inner.consecutive_failures >= self.config.failure_threshold => {
+                warn!(
+                    "Database circuit breaker opened after {} consecutive failures",
+                    inner.consecutive_failures
+                );
+                Self::transition(&mut inner, CircuitState::Open);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn status(&self) -> CircuitBreakerStatus {
+        let mut inner = self.inner.lock().unwrap();
+        self.refresh(&mut inner);
+
+        let retry_in_seconds = match (inner.state, inner.opened_at) {
+            (CircuitState::Open, Some(opened_at)) => Some(
+                self.open_duration()
+                    .saturating_sub(opened_at.elapsed())
+                    .as_secs(),
+            ),
+            _ => None,
+        };
+
+        CircuitBreakerStatus {
+            state: inner.state,
+            policy: self.config.open_policy,
+            consecutive_failures: inner.consecutive_failures,
+            failure_threshold: self.config.failure_threshold,
+            rejected_calls: inner.rejected_calls,
+            last_state_change: inner.last_state_change.to_rfc3339(),
+            retry_in_seconds,
+        }
+    }
+
+    fn open_duration(&self) -> Duration {
+        Duration::from_secs(self.config.open_seconds)
+    }
+
+    fn refresh(&self, inner: &mut BreakerInner) {
+        if inner.state == CircuitState::Open
+            && inner
+                .opened_at
+                .is_some_and(|opened_at| opened_at.elapsed() >= self.open_duration())
+        {
+            info!("Database circuit breaker half-open, allowing trial calls");
+            Self::transition(inner, CircuitState::HalfOpen);
+        }
+    }
+
+    fn transition(inner: &mut BreakerInner, state: CircuitState) {
+        inner.state = state;
+        inner.last_state_change = Utc::now();
+        inner.half_open_in_flight = 0;
+        inner.opened_at = match state {
+            CircuitState::Open => Some(Instant::now()),
+            _ => None,
+        };
+    }
+}
@@ -1,11 +1,158 @@
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, ensure};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub app: AppConfig,
     pub database: DatabaseConfig,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    #[serde(default)]
+    pub webhooks: WebhooksConfig,
+    #[serde(default)]
+    pub health: HealthConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub statsd: StatsdConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+}
+
+/// Log output settings; the root level is `app.log_level`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    /// Write to stdout (stderr for CLI commands)
+    pub console: bool,
+    pub file: FileLogConfig,
+    /// Level per log target, e.g. `"axum_health_service::app" = "debug"`,
+    /// applied to the target and everything under it
+    pub levels: BTreeMap<String, String>,
+    /// log4rs `.yaml`, `.toml`, or `.json` file used instead of the settings
+    /// above when it loads
+    pub config_file: Option<String>,
+    /// How often `config_file` is checked for changes; 0 reads it once
+    pub config_refresh_seconds: u64,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::default(),
+            console: true,
+            file: FileLogConfig::default(),
+            levels: BTreeMap::new(),
+            config_file: None,
+            config_refresh_seconds: 30,
+        }
+    }
+}
+
+/// How each log event is written, to the console and the file alike
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One human-readable line, with structured fields as `key=value`
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Log file that is rolled over by size and/or age
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FileLogConfig {
+    pub enabled: bool,
+    pub path: String,
+    /// Roll once the file is larger than this
+    pub max_size_mb: Option<u64>,
+    /// Roll on a schedule such as `"1 day"` or `"6 hours"`
+    pub roll_interval: Option<String>,
+    /// Rolled files kept as `<path>.1` (newest) to `<path>.<keep>`; 0 keeps none
+    pub keep: u32,
+    /// Gzip rolled files
+    pub compress: bool,
+}
+
+impl Default for FileLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "logs/axum-health-service.log".to_string(),
+            max_size_mb: Some(100),
+            roll_interval: None,
+            keep: 7,
+            compress: true,
+        }
+    }
+}
+
+/// Listener for operational endpoints such as `/metrics`, kept off the public port
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdminConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            host: "127.0.0.1".to_string(),
+            port: 9090,
+        }
+    }
+}
+
+/// Optional push of counters and timers to a StatsD agent over UDP
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StatsdConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub flavor: StatsdFlavor,
+    /// Prepended to every metric name, e.g. `"heartbeats."`
+    pub prefix: String,
+    /// Longest a metric waits in the batch before the packet is sent
+    pub flush_interval_ms: u64,
+    /// Largest packet sent; the default fits in an Ethernet MTU
+    pub max_packet_bytes: usize,
+    /// Timer lines waiting to be sent; beyond this new ones are dropped
+    pub queue_capacity: usize,
+}
+
+impl Default for StatsdConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: "127.0.0.1".to_string(),
+            port: 8125,
+            flavor: StatsdFlavor::default(),
+            prefix: String::new(),
+            flush_interval_ms: 1000,
+            max_packet_bytes: 1432,
+            queue_capacity: 10_000,
+        }
+    }
+}
+
+/// How labels are sent to the StatsD agent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsdFlavor {
+    /// `|#key:value` tags, including `service` and `version`
+    #[default]
+    Dogstatsd,
+    /// Plain StatsD has no tags, so label values are appended to the name
+    Statsd,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,6 +162,9 @@ pub struct AppConfig {
     pub host: String,
     pub port: u16,
     pub log_level: String,
+    /// Requests slower than this are logged with a per-phase breakdown
+    #[serde(default = "default_slow_request_ms")]
+    pub slow_request_ms: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -26,6 +176,274 @@ pub struct DatabaseConfig {
     pub database: String,
     pub pool_size: u32,
     pub timeout_seconds: u64,
+    /// Connections the pool opens up front and keeps around
+    #[serde(default = "default_min_idle")]
+    pub min_idle: u32,
+    /// TCP keepalive time for database sockets, disabled when 0
+    #[serde(default = "default_tcp_keepalive_seconds")]
+    pub tcp_keepalive_seconds: u32,
+    /// Prepared statements cached per connection
+    #[serde(default = "default_stmt_cache_size")]
+    pub stmt_cache_size: usize,
+    /// Maximum number of database operations waiting for a worker thread
+    #[serde(default = "default_queue_capacity")]
+    pub queue_capacity: usize,
+    /// Maximum number of heartbeats waiting to be persisted
+    #[serde(default = "default_writer_queue_capacity")]
+    pub writer_queue_capacity: usize,
+    /// How long a device authorized by the database is served from the
+    /// device cache before `is_device_active` is asked again
+    ///
+    /// Only applies while the database answers. When it cannot be asked and
+    /// the open-circuit policy is fail-open, expired entries are still
+    /// accepted; under fail-closed they are rejected like any other device.
+    #[serde(default = "default_device_cache_ttl_seconds")]
+    pub device_cache_ttl_seconds: u64,
+    /// Exports allowed at once; each holds a database worker while it runs
+    #[serde(default = "default_max_concurrent_exports")]
+    pub max_concurrent_exports: usize,
+    #[serde(default)]
+    pub migrations: MigrationMode,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    #[serde(default)]
+    pub backend: StoreBackend,
+    /// MAC addresses registered as active devices when `backend = "memory"`
+    #[serde(default)]
+    pub memory_devices: Vec<String>,
+}
+
+/// Scheduled rollup and deletion of old raw heartbeats
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetentionConfig {
+    pub enabled: bool,
+    /// Raw heartbeats older than this are rolled up into hourly rows and deleted
+    pub max_age_hours: u64,
+    /// How often the retention job runs
+    pub interval_seconds: u64,
+    /// Hours of raw heartbeats summarized per rollup statement
+    pub rollup_batch_hours: u64,
+    /// Rows deleted per DELETE statement
+    pub batch_size: usize,
+    /// Pause between rollup and delete batches so other queries get a turn
+    pub batch_pause_ms: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            // Keep a little over a month of raw rows for heartbeat history
+            max_age_hours: 31 * 24,
+            interval_seconds: 3600,
+            rollup_batch_hours: 1,
+            batch_size: 1000,
+            batch_pause_ms: 100,
+        }
+    }
+}
+
+/// Thresholds for the component checks behind `/health` and `/readyz`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HealthConfig {
+    /// How often the component checks run in the background
+    pub probe_interval_seconds: u64,
+    /// Longest a single component check may take
+    pub timeout_ms: u64,
+    /// Status transitions kept for `/health`
+    pub history_size: usize,
+    /// Any path on the volume whose free space is checked
+    pub disk_path: String,
+    pub disk_degraded_free_percent: f64,
+    pub disk_unhealthy_free_percent: f64,
+    /// Writer queue fill level reported as degraded
+    pub writer_queue_degraded_percent: f64,
+    /// HTTP status `/health` returns while degraded
+    pub degraded_status_code: u16,
+    /// HTTP status `/health` returns while unhealthy
+    pub unhealthy_status_code: u16,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            probe_interval_seconds: 5,
+            timeout_ms: 2000,
+            history_size: 20,
+            disk_path: ".".to_string(),
+            disk_degraded_free_percent: 10.0,
+            disk_unhealthy_free_percent: 5.0,
+            writer_queue_degraded_percent: 80.0,
+            degraded_status_code: 200,
+            unhealthy_status_code: 200,
+        }
+    }
+}
+
+/// Webhook notifications for device state changes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebhooksConfig {
+    pub subscriptions: Vec<WebhookSubscription>,
+    /// NDJSON file that deliveries are appended to once retries are exhausted
+    pub dead_letter_path: String,
+    /// Delivery attempts per event and subscription, including the first
+    pub max_attempts: u32,
+    /// Delay before the first retry; doubled for every retry after that
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub timeout_seconds: u64,
+    /// How often devices are checked for missed heartbeats
+    pub offline_check_seconds: u64,
+    /// Maximum number of events waiting to be dispatched
+    pub queue_capacity: usize,
+    /// Deliveries, including their retries, in progress at once
+    pub max_concurrent_deliveries: usize,
+    /// A device raises at most one `rejected` event per interval
+    pub rejected_interval_seconds: u64,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        Self {
+            subscriptions: Vec::new(),
+            dead_letter_path: "webhooks-dead-letter.ndjson".to_string(),
+            max_attempts: 6,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 60_000,
+            timeout_seconds: 10,
+            offline_check_seconds: 30,
+            queue_capacity: 1024,
+            max_concurrent_deliveries: 16,
+            rejected_interval_seconds: 300,
+        }
+    }
+}
+
+/// A webhook receiver and the events it wants
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookSubscription {
+    pub url: String,
+    /// Key for the `X-Webhook-Signature` HMAC-SHA256 header
+    pub secret: String,
+    /// Events delivered to this receiver; all events when empty
+    #[serde(default)]
+    pub events: Vec<WebhookEventKind>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEventKind {
+    /// A device missed its expected heartbeats
+    Offline,
+    /// A device that was offline sent a heartbeat again
+    Online,
+    /// A device's reported or public IP changed
+    IpChanged,
+    /// A heartbeat was refused by `is_device_active`
+    Rejected,
+}
+
+/// What to do at startup when the database schema is behind the binary
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MigrationMode {
+    /// Apply pending migrations
+    Apply,
+    /// Refuse to start until the schema has been migrated
+    #[default]
+    Verify,
+    /// Do not look at the schema at all
+    Off,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failures before the circuit opens
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before trial calls are allowed
+    pub open_seconds: u64,
+    /// Trial calls allowed at once while half-open
+    pub half_open_max_calls: u32,
+    /// How heartbeats are authorized while the circuit is open
+    pub open_policy: OpenCircuitPolicy,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_seconds: 30,
+            half_open_max_calls: 1,
+            open_policy: OpenCircuitPolicy::FailOpen,
+        }
+    }
+}
+
+/// Heartbeat authorization while the database circuit is open
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OpenCircuitPolicy {
+    /// Accept devices already in the device cache, however old their entry,
+    /// and reject unknown ones
+    #[default]
+    FailOpen,
+    /// Reject every heartbeat until the database is reachable again
+    FailClosed,
+}
+
+fn default_slow_request_ms() -> u64 {
+    500
+}
+
+fn default_min_idle() -> u32 {
+    1
+}
+
+fn default_tcp_keepalive_seconds() -> u32 {
+    60
+}
+
+fn default_stmt_cache_size() -> usize {
+    32
+}
+
+fn default_queue_capacity() -> usize {
+    1024
+}
+
+fn default_writer_queue_capacity() -> usize {
+    10_000
+}
+
+fn default_device_cache_ttl_seconds() -> u64 {
+    300
+}
+
+fn default_max_concurrent_exports() -> usize {
+    2
+}
+
+/// Which `DeviceStore` implementation the service runs against
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreBackend {
+    #[default]
+    Mysql,
+    Memory,
+}
+
+impl DatabaseConfig {
+    /// Keepalive time in the milliseconds the driver expects, `None` when disabled
+    pub fn tcp_keepalive_ms(&self) -> Option<u32> {
+        match self.tcp_keepalive_seconds {
+            0 => None,
+            secs => secs.checked_mul(1000),
+        }
+    }
 }
 
 impl Default for Config {
@@ -37,6 +455,7 @@ impl Default for Config {
                 host: "0.0.0.0".to_string(),
                 port: 3000,
                 log_level: "info".to_string(),
+                slow_request_ms: default_slow_request_ms(),
             },
             database: DatabaseConfig {
                 host: "localhost".to_string(),
@@ -46,7 +465,24 @@ impl Default for Config {
                 database: "health_service".to_string(),
                 pool_size: 10,
                 timeout_seconds: 30,
+                min_idle: default_min_idle(),
+                tcp_keepalive_seconds: default_tcp_keepalive_seconds(),
+                stmt_cache_size: default_stmt_cache_size(),
+                queue_capacity: default_queue_capacity(),
+                writer_queue_capacity: default_writer_queue_capacity(),
+                device_cache_ttl_seconds: default_device_cache_ttl_seconds(),
+                max_concurrent_exports: default_max_concurrent_exports(),
+                migrations: MigrationMode::Verify,
+                circuit_breaker: CircuitBreakerConfig::default(),
+                backend: StoreBackend::Mysql,
+                memory_devices: Vec::new(),
             },
+            retention: RetentionConfig::default(),
+            webhooks: WebhooksConfig::default(),
+            health: HealthConfig::default(),
+            admin: AdminConfig::default(),
+            statsd: StatsdConfig::default(),
+            logging: LoggingConfig::default(),
         }
     }
 }
@@ -87,6 +523,27 @@ impl Config {
         }
     }
 
+    /// Reject settings that would leave a component unable to work
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.database.circuit_breaker.half_open_max_calls >= 1,
+            "database.circuit_breaker.half_open_max_calls must be at least 1"
+        );
+        ensure!(
+            self.database.max_concurrent_exports < self.database.pool_size as usize,
+            "database.max_concurrent_exports ({}) must be below database.pool_size ({}) \
+             so exports cannot take every database worker",
+            self.database.max_concurrent_exports,
+            self.database.pool_size
+        );
+        ensure!(
+            self.database.tcp_keepalive_ms().is_some() || self.database.tcp_keepalive_seconds == 0,
+            "database.tcp_keepalive_seconds is too large ({})",
+            self.database.tcp_keepalive_seconds
+        );
+        Ok(())
+    }
+
     /// Get database connection string
     pub fn database_url(&self) -> String {
         format!(
@@ -103,4 +560,9 @@ impl Config {
     pub fn bind_address(&self) -> String {
         format!("{}:{}", self.app.host, self.app.port)
     }
+
+    /// Get admin listener bind address
+    pub fn admin_bind_address(&self) -> String {
+        format!("{}:{}", self.admin.host, self.admin.port)
+    }
 }
@@ -0,0 +1,202 @@
+use anyhow::Result;
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+use log::{error, info};
+use serde::Serialize;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Instant;
+use tokio::sync::oneshot;
+
+use crate::breaker::{CircuitBreaker, CircuitOpenError};
+use crate::logging::DEBUG_MAC_KEY;
+use crate::mdc;
+use crate::metrics::{DB_LATENCY_BUCKETS, MetricsRegistry};
+use crate::request_id;
+use crate::store::{DeviceStore, PoolStats};
+
+type Job = Box<dyn FnOnce(&dyn DeviceStore) + Send>;
+
+/// Returned by an operation that stopped early because its caller went away
+///
+/// Counts as neither a success nor a failure of the database.
+#[derive(Debug)]
+pub struct CallerGone;
+
+impl std::fmt::Display for CallerGone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Caller went away before the operation finished")
+    }
+}
+
+impl std::error::Error for CallerGone {}
+
+/// Snapshot of the database worker queue
+#[derive(Clone, Debug, Serialize)]
+pub struct DbExecutorStats {
+    pub workers: usize,
+    pub queue_capacity: usize,
+    pub queued: usize,
+    pub in_flight: usize,
+}
+
+/// Runs `DeviceStore` calls on a fixed set of dedicated OS threads
+///
+/// The `mysql` crate is blocking, so async handlers must never call the store
+/// directly. Jobs are queued on a bounded channel and picked up by one of
+/// `workers` threads, which caps the number of concurrent database operations.
+/// Every operation also goes through the circuit breaker, so a dead database
+/// is not hammered with calls that are bound to fail.
+#[derive(Clone)]
+pub struct DbExecutor {
+    sender: Sender<Job>,
+    store: Arc<dyn DeviceStore>,
+    breaker: Arc<CircuitBreaker>,
+    in_flight: Arc<AtomicUsize>,
+    metrics: Arc<MetricsRegistry>,
+    workers: usize,
+    queue_capacity: usize,
+}
+
+impl DbExecutor {
+    pub fn new(
+        store: Arc<dyn DeviceStore>,
+        workers: usize,
+        queue_capacity: usize,
+        breaker: CircuitBreaker,
+        metrics: Arc<MetricsRegistry>,
+    ) -> Self {
+        let workers = workers.max(1);
+        let (sender, receiver) = channel::bounded::<Job>(queue_capacity);
+        let in_flight = Arc::new(AtomicUsize::new(0));
+
+        for worker_id in 0..workers {
+            let receiver = receiver.clone();
+            let store = store.clone();
+            let in_flight = in_flight.clone();
+            thread::Builder::new()
+                .name(format!("db-worker-{}", worker_id))
+                .spawn(move || Self::worker_loop(receiver, store, in_flight))
+                .expect("failed to spawn database worker thread");
+        }
+
+        info!(
+            "Database executor started: workers={}, queue_capacity={}",
+            workers, queue_capacity
+        );
+
+        Self {
+            sender,
+            store,
+            breaker: Arc::new(breaker),
+            in_flight,
+            metrics,
+            workers,
+            queue_capacity,
+        }
+    }
+
+    fn worker_loop(
+        receiver: Receiver<Job>,
+        store: Arc<dyn DeviceStore>,
+        in_flight: Arc<AtomicUsize>,
+    ) {
+        // Exits once every `DbExecutor` clone (and so every sender) is dropped
+        while let Ok(job) = receiver.recv() {
+            in_flight.fetch_add(1, Ordering::Relaxed);
+            job(store.as_ref());
+            in_flight.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Run `op` against the store on a database worker and wait for its result
+    ///
+    /// `operation` names the call in the latency and error metrics.
+    ///
+    /// Fails immediately with `CircuitOpenError` while the circuit is open, and
+    /// when the queue is full instead of piling up requests.
+    pub async fn run<T, F>(&self, operation: &'static str, op: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn DeviceStore) -> Result<T> + Send + 'static,
+    {
+        if !self.breaker.try_acquire() {
+            return Err(CircuitOpenError.into());
+        }
+
+        let latency = self.metrics.histogram(
+            "db_query_duration_seconds",
+            "Time spent running database operations on a worker",
+            DB_LATENCY_BUCKETS,
+            &[("operation", operation)],
+        );
+        let errors = self.metrics.counter(
+            "db_query_errors_total",
+            "Database operations that returned an error",
+            &[("operation", operation)],
+        );
+
+        let (tx, rx) = oneshot::channel();
+        let breaker = self.breaker.clone();
+        let request_id = request_id::current();
+        // Keeps per-device debug logging on for the store's own records
+        let debug_mac = mdc::mdc_get(DEBUG_MAC_KEY);
+        let job: Job = Box::new(move |store| {
+            let started = Instant::now();
+            let result = request_id::scope(request_id, || {
+                mdc::mdc_scope(DEBUG_MAC_KEY, debug_mac, || op(store))
+            });
+            latency.observe(started.elapsed().as_secs_f64());
+            // Recorded here rather than in the caller so an abandoned request
+            // still releases its half-open trial slot
+            match &result {
+                Ok(_) => breaker.record_success(),
+                Err(e) if e.is::<CallerGone>() => breaker.release(),
+                Err(_) => {
+                    errors.inc();
+                    breaker.record_failure();
+                }
+            }
+            // The caller may have gone away; nothing to do in that case
+            let _ = tx.send(result);
+        });
+
+        match self.sender.try_send(job) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                // A busy queue says nothing about the database itself
+                self.breaker.release();
+                error!(
+                    "Database queue full ({} queued), rejecting operation",
+                    self.queue_capacity
+                );
+                return Err(anyhow::anyhow!("Database queue is full"));
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                self.breaker.record_failure();
+                return Err(anyhow::anyhow!("Database workers have shut down"));
+            }
+        }
+
+        rx.await
+            .map_err(|_| anyhow::anyhow!("Database worker dropped the operation"))?
+    }
+
+    pub fn breaker(&self) -> &CircuitBreaker {
+        &self.breaker
+    }
+
+    /// Connection pool usage of the underlying store
+    pub fn pool_stats(&self) -> Option<PoolStats> {
+        self.store.pool_stats()
+    }
+
+    pub fn stats(&self) -> DbExecutorStats {
+        DbExecutorStats {
+            workers: self.workers,
+            queue_capacity: self.queue_capacity,
+            queued: self.sender.len(),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,365 @@
+use anyhow::{Context, Result};
+use axum::{
+    body::Body,
+    http::{HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use futures_util::stream;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use tokio::sync::mpsc;
+
+use crate::app::{ApiError, api_error, parse_time, parse_time_param};
+use crate::executor::CallerGone;
+use crate::mdc::WithMdc;
+use crate::request_id;
+use crate::server::AppState;
+use crate::store::{DeviceRecord, DeviceStore, ExportFilter, StoredHeartbeat};
+
+/// Rows buffered between the database worker and the HTTP response
+const EXPORT_CHANNEL_ROWS: usize = 256;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Ndjson,
+}
+
+impl ExportFormat {
+    fn parse(value: &str) -> Result<Self> {
+        match value {
+            "csv" => Ok(Self::Csv),
+            "ndjson" => Ok(Self::Ndjson),
+            other => anyhow::bail!("unknown export format: {}", other),
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Ndjson => "ndjson",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportDataset {
+    Heartbeats,
+    Devices,
+}
+
+impl ExportDataset {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Heartbeats => "heartbeats",
+            Self::Devices => "devices",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+    pub format: Option<ExportFormat>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub account: Option<i32>,
+    pub mac_prefix: Option<String>,
+}
+
+/// A row that can be written as CSV as well as NDJSON
+trait ExportRow: Serialize {
+    const CSV_HEADER: &'static [&'static str];
+
+    fn csv_fields(&self) -> Vec<String>;
+}
+
+impl ExportRow for StoredHeartbeat {
+    const CSV_HEADER: &'static [&'static str] = &[
+        "id",
+        "device_id",
+        "mac",
+        "reported_ip",
+        "public_ip",
+        "lp",
+        "device_ts",
+        "received_at",
+    ];
+
+    fn csv_fields(&self) -> Vec<String> {
+        let record = &self.record;
+        vec![
+            self.id.to_string(),
+            record.device_id.to_string(),
+            record.mac.clone(),
+            record.reported_ip.clone(),
+            record.public_ip.clone(),
+            record.lp.map(|lp| lp.to_string()).unwrap_or_default(),
+            record
+                .device_ts
+                .map(|ts| ts.to_string())
+                .unwrap_or_default(),
+            record.received_at.to_rfc3339(),
+        ]
+    }
+}
+
+impl ExportRow for DeviceRecord {
+    const CSV_HEADER: &'static [&'static str] = &["mac", "account_id", "active", "squelched"];
+
+    fn csv_fields(&self) -> Vec<String> {
+        vec![
+            self.mac.clone(),
+            self.account_id.map(|id| id.to_string()).unwrap_or_default(),
+            self.active.to_string(),
+            self.squelched.to_string(),
+        ]
+    }
+}
+
+fn csv_line(fields: &[String]) -> String {
+    let mut line = fields
+        .iter()
+        .map(|field| {
+            if field.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.clone()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    line.push('\n');
+    line
+}
+
+fn encode_row<T: ExportRow>(row: &T, format: ExportFormat) -> Result<String> {
+    match format {
+        ExportFormat::Csv => Ok(csv_line(&row.csv_fields())),
+        ExportFormat::Ndjson => {
+            let mut line = serde_json::to_string(row)?;
+            line.push('\n');
+            Ok(line)
+        }
+    }
+}
+
+fn encode_header<T: ExportRow>(format: ExportFormat) -> Option<String> {
+    match format {
+        ExportFormat::Csv => {
+            let header: Vec<String> = T::CSV_HEADER.iter().map(|h| h.to_string()).collect();
+            Some(csv_line(&header))
+        }
+        ExportFormat::Ndjson => None,
+    }
+}
+
+/// Run an export against the store, handing each encoded line to `emit`
+///
+/// Shared by the HTTP endpoints and the CLI; blocks on the store.
+fn write_export(
+    store: &dyn DeviceStore,
+    dataset: ExportDataset,
+    format: ExportFormat,
+    filter: &ExportFilter,
+    emit: &mut dyn FnMut(String) -> Result<()>,
+) -> Result<()> {
+    match dataset {
+        ExportDataset::Heartbeats => {
+            if let Some(header) = encode_header::<StoredHeartbeat>(format) {
+                emit(header)?;
+            }
+            store.export_heartbeats(filter, &mut |row| emit(encode_row(&row, format)?))
+        }
+        ExportDataset::Devices => {
+            if let Some(header) = encode_header::<DeviceRecord>(format) {
+                emit(header)?;
+            }
+            store.export_devices(filter, &mut |row| emit(encode_row(&row, format)?))
+        }
+    }
+}
+
+/// Business logic for the export endpoints
+pub struct ExportService;
+
+impl ExportService {
+    /// Stream a dataset as the response body, one row per chunk
+    pub async fn export(
+        state: &AppState,
+        dataset: ExportDataset,
+        params: ExportParams,
+    ) -> Result<Response, ApiError> {
+        let format = params.format.unwrap_or_default();
+        let filter = ExportFilter {
+            from: params
+                .from
+                .as_deref()
+                .map(|value| parse_time_param("from", value))
+                .transpose()?,
+            to: params
+                .to
+                .as_deref()
+                .map(|value| parse_time_param("to", value))
+                .transpose()?,
+            account_id: params.account,
+            mac_prefix: params.mac_prefix,
+        };
+        // Each export holds a database worker until the download finishes
+        let Ok(permit) = state.exports.clone().try_acquire_owned() else {
+            info!(
+                "Export of {} refused: too many exports running",
+                dataset.name()
+            );
+            return Err(api_error(
+                StatusCode::TOO_MANY_REQUESTS,
+                "too many exports running, try again later",
+            ));
+        };
+        info!(
+            "Export of {} started: format={:?}, filter={:?}",
+            dataset.name(),
+            format,
+            filter
+        );
+
+        // The worker blocks on the bounded channel, so a slow client slows the
+        // query down instead of rows piling up in memory
+        let (tx, rx) = mpsc::channel::<Result<String, std::io::Error>>(EXPORT_CHANNEL_ROWS);
+        let db = state.db.clone();
+        // The stream outlives the request future, so the id is carried over
+        // here and from there onto the database worker by `DbExecutor::run`
+        let request_id = request_id::current();
+        tokio::spawn(WithMdc::new(request_id::MDC_KEY, request_id, async move {
+            let error_tx = tx.clone();
+            let result = db
+                .run("export", move |store| {
+                    write_export(store, dataset, format, &filter, &mut |line| {
+                        tx.blocking_send(Ok(line)).map_err(|_| CallerGone.into())
+                    })
+                })
+                .await;
+            drop(permit);
+            match result {
+                Ok(()) => info!("Export of {} finished", dataset.name()),
+                Err(e) if e.is::<CallerGone>() => {
+                    info!("Export of {} stopped: client disconnected", dataset.name())
+                }
+                Err(e) => {
+                    error!("Export of {} failed: {}", dataset.name(), e);
+                    // Aborts the response so the client sees a truncated body
+                    let _ = error_tx
+                        .send(Err(std::io::Error::other(e.to_string())))
+                        .await;
+                }
+            }
+        }));
+
+        let body = Body::from_stream(stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|item| (item, rx))
+        }));
+
+        let disposition = format!(
+            "attachment; filename=\"{}.{}\"",
+            dataset.name(),
+            format.extension()
+        );
+        let mut response = (StatusCode::OK, body).into_response();
+        let headers = response.headers_mut();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(format.content_type()),
+        );
+        if let Ok(value) = HeaderValue::from_str(&disposition) {
+            headers.insert(header::CONTENT_DISPOSITION, value);
+        }
+        Ok(response)
+    }
+}
+
+/// Arguments of the `export` subcommand
+pub struct ExportCommand {
+    pub dataset: ExportDataset,
+    pub format: ExportFormat,
+    pub filter: ExportFilter,
+    pub output: Option<String>,
+}
+
+impl ExportCommand {
+    pub const USAGE: &'static str = "usage: export <heartbeats|devices> [--format csv|ndjson] \
+        [--from TIME] [--to TIME] [--account ID] [--mac-prefix PREFIX] [--output FILE]";
+
+    /// Parse the arguments that follow `export` on the command line
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let dataset = match args.first().map(String::as_str) {
+            Some("heartbeats") => ExportDataset::Heartbeats,
+            Some("devices") => ExportDataset::Devices,
+            _ => anyhow::bail!("{}", Self::USAGE),
+        };
+
+        let mut command = Self {
+            dataset,
+            format: ExportFormat::default(),
+            filter: ExportFilter::default(),
+            output: None,
+        };
+
+        let mut rest = args[1..].iter();
+        while let Some(flag) = rest.next() {
+            let value = rest
+                .next()
+                .with_context(|| format!("{} needs a value\n{}", flag, Self::USAGE))?;
+            match flag.as_str() {
+                "--format" => command.format = ExportFormat::parse(value)?,
+                "--from" => {
+                    command.filter.from =
+                        Some(parse_time("--from", value).map_err(anyhow::Error::msg)?)
+                }
+                "--to" => {
+                    command.filter.to = Some(parse_time("--to", value).map_err(anyhow::Error::msg)?)
+                }
+                "--account" => {
+                    command.filter.account_id =
+                        Some(value.parse().context("--account must be a number")?)
+                }
+                "--mac-prefix" => command.filter.mac_prefix = Some(value.clone()),
+                "--output" => command.output = Some(value.clone()),
+                other => anyhow::bail!("unknown option {}\n{}", other, Self::USAGE),
+            }
+        }
+        Ok(command)
+    }
+
+    /// Write the export to `--output` or stdout
+    pub fn run(&self, store: &dyn DeviceStore) -> Result<()> {
+        let mut out: Box<dyn Write> = match &self.output {
+            Some(path) => Box::new(std::io::BufWriter::new(
+                std::fs::File::create(path)
+                    .with_context(|| format!("Failed to create {}", path))?,
+            )),
+            None => Box::new(std::io::BufWriter::new(std::io::stdout().lock())),
+        };
+
+        write_export(
+            store,
+            self.dataset,
+            self.format,
+            &self.filter,
+            &mut |line| {
+                out.write_all(line.as_bytes())?;
+                Ok(())
+            },
+        )?;
+        out.flush()?;
+        Ok(())
+    }
+}
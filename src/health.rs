@@ -0,0 +1,589 @@
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use futures_util::future::{BoxFuture, join_all};
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::app::{CacheWarmup, WarmupState};
+use crate::config::HealthConfig;
+use crate::executor::DbExecutor;
+use crate::writer::HeartbeatWriter;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Healthy,
+    Degraded,
+    Unhealthy,
+}
+
+impl HealthStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Healthy => "healthy",
+            Self::Degraded => "degraded",
+            Self::Unhealthy => "unhealthy",
+        }
+    }
+}
+
+/// How much a component's failure counts toward the overall status
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Criticality {
+    /// Unhealthy makes the service unhealthy and not ready
+    Critical,
+    /// Unhealthy only degrades the service
+    NonCritical,
+}
+
+/// What a single check found
+pub struct CheckOutcome {
+    pub status: HealthStatus,
+    pub error: Option<String>,
+    pub details: Option<serde_json::Value>,
+    /// False keeps the service out of rotation whatever the status
+    pub ready: bool,
+}
+
+impl CheckOutcome {
+    pub fn healthy() -> Self {
+        Self {
+            status: HealthStatus::Healthy,
+            error: None,
+            details: None,
+            ready: true,
+        }
+    }
+
+    pub fn degraded(error: impl Into<String>) -> Self {
+        Self {
+            status: HealthStatus::Degraded,
+            error: Some(error.into()),
+            details: None,
+            ready: true,
+        }
+    }
+
+    pub fn unhealthy(error: impl Into<String>) -> Self {
+        Self {
+            status: HealthStatus::Unhealthy,
+            error: Some(error.into()),
+            details: None,
+            ready: true,
+        }
+    }
+
+    /// Not ready for traffic yet, without counting toward the `/health` status
+    pub fn not_ready(mut self) -> Self {
+        self.ready = false;
+        self
+    }
+
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+}
+
+/// A named component of the service that can report its own health
+pub trait HealthCheck: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn criticality(&self) -> Criticality;
+
+    /// Longest the check may take; the registry default when `None`
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    fn check(&self) -> BoxFuture<'_, CheckOutcome>;
+}
+
+/// Result of one component, as reported by `/health` and `/readyz`
+#[derive(Clone, Debug, Serialize)]
+pub struct ComponentHealth {
+    pub name: String,
+    pub status: HealthStatus,
+    pub criticality: Criticality,
+    /// False when the component keeps `/readyz` from answering 200
+    pub ready: bool,
+    pub latency_ms: u64,
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+/// The set of health checks the service runs
+pub struct HealthRegistry {
+    checks: Vec<Box<dyn HealthCheck>>,
+    default_timeout: Duration,
+}
+
+impl HealthRegistry {
+    pub fn new(default_timeout: Duration) -> Self {
+        Self {
+            checks: Vec::new(),
+            default_timeout,
+        }
+    }
+
+    pub fn register(&mut self, check: impl HealthCheck + 'static) {
+        info!(
+            check = check.name(),
+            criticality:serde = check.criticality();
+            "Registered health check"
+        );
+        self.checks.push(Box::new(check));
+    }
+
+    /// Run every check concurrently, each bounded by its timeout
+    pub async fn run_all(&self) -> Vec<ComponentHealth> {
+        join_all(self.checks.iter().map(|check| self.run_one(check.as_ref()))).await
+    }
+
+    async fn run_one(&self, check: &dyn HealthCheck) -> ComponentHealth {
+        let timeout = check.timeout().unwrap_or(self.default_timeout);
+        let start = Instant::now();
+        let outcome = match tokio::time::timeout(timeout, check.check()).await {
+            Ok(outcome) => outcome,
+            Err(_) => {
+                CheckOutcome::unhealthy(format!("check timed out after {}ms", timeout.as_millis()))
+            }
+        };
+        if let Some(error) = &outcome.error {
+            warn!(
+                check = check.name(),
+                status = outcome.status.as_str(),
+                error = error.as_str();
+                "Health check not healthy"
+            );
+        }
+
+        ComponentHealth {
+            name: check.name().to_string(),
+            status: outcome.status,
+            criticality: check.criticality(),
+            ready: outcome.ready
+                && !(check.criticality() == Criticality::Critical
+                    && outcome.status == HealthStatus::Unhealthy),
+            latency_ms: start.elapsed().as_millis() as u64,
+            error: outcome.error,
+            details: outcome.details,
+        }
+    }
+}
+
+/// Overall status: the worst component, with non-critical failures capped at degraded
+pub fn overall_status(components: &[ComponentHealth]) -> HealthStatus {
+    components
+        .iter()
+        .map(|component| match component.criticality {
+            Criticality::Critical => component.status,
+            Criticality::NonCritical => component.status.min(HealthStatus::Degraded),
+        })
+        .max()
+        .unwrap_or(HealthStatus::Healthy)
+}
+
+/// One run of every component check
+#[derive(Clone, Debug, Serialize)]
+pub struct HealthSnapshot {
+    pub status: HealthStatus,
+    pub checked_at: DateTime<Utc>,
+    pub components: Vec<ComponentHealth>,
+}
+
+impl HealthSnapshot {
+    pub fn age_ms(&self) -> u64 {
+        (Utc::now() - self.checked_at).num_milliseconds().max(0) as u64
+    }
+}
+
+/// A change in the status of one component, or of the service (`overall`)
+#[derive(Clone, Debug, Serialize)]
+pub struct HealthTransition {
+    pub at: String,
+    pub component: String,
+    pub from: HealthStatus,
+    pub to: HealthStatus,
+    pub error: Option<String>,
+}
+
+/// Runs the health checks on an interval and keeps the latest result, so
+/// `/health` and `/readyz` never wait on the database
+pub struct HealthMonitor {
+    registry: HealthRegistry,
+    latest: Mutex<Option<HealthSnapshot>>,
+    transitions: Mutex<VecDeque<HealthTransition>>,
+    history_size: usize,
+    degraded_status_code: StatusCode,
+    unhealthy_status_code: StatusCode,
+    /// Results younger than this are served to `?refresh=true` as they are
+    min_refresh_interval: Duration,
+    /// Held while a requested refresh runs, so concurrent requests share it
+    requested_refresh: tokio::sync::Mutex<()>,
+}
+
+impl HealthMonitor {
+    /// Start probing on the current tokio runtime
+    pub fn spawn(registry: HealthRegistry, config: &HealthConfig) -> Arc<Self> {
+        let monitor = Arc::new(Self {
+            registry,
+            latest: Mutex::new(None),
+            transitions: Mutex::new(VecDeque::new()),
+            history_size: config.history_size,
+            degraded_status_code: status_code_or(config.degraded_status_code, StatusCode::OK),
+            unhealthy_status_code: status_code_or(config.unhealthy_status_code, StatusCode::OK),
+            min_refresh_interval: Duration::from_millis(config.probe_interval_seconds * 500),
+            requested_refresh: tokio::sync::Mutex::new(()),
+        });
+        info!(
+            probe_interval_seconds = config.probe_interval_seconds,
+            history_size = config.history_size;
+            "Health checks scheduled"
+        );
+
+        let task = monitor.clone();
+        let interval = Duration::from_secs(config.probe_interval_seconds.max(1));
+        tokio::spawn(async move {
+            loop {
+                task.refresh().await;
+                tokio::time::sleep(interval).await;
+            }
+        });
+
+        monitor
+    }
+
+    /// The last background result, or a fresh one if there is none yet
+    pub async fn latest(&self) -> HealthSnapshot {
+        let latest = self.latest.lock().unwrap().clone();
+        match latest {
+            Some(snapshot) => snapshot,
+            None => self.refresh().await,
+        }
+    }
+
+    /// Run every check now and make the result the latest
+    pub async fn refresh(&self) -> HealthSnapshot {
+        let components = self.registry.run_all().await;
+        let snapshot = HealthSnapshot {
+            status: overall_status(&components),
+            checked_at: Utc::now(),
+            components,
+        };
+        self.record(&snapshot);
+        snapshot
+    }
+
+    /// Run every check for a client that asked for fresh results
+    ///
+    /// Anyone can ask, so a result less than half a probe interval old is
+    /// returned instead of hitting the database again.
+    pub async fn refresh_requested(&self) -> HealthSnapshot {
+        let _refreshing = self.requested_refresh.lock().await;
+        let latest = self.latest.lock().unwrap().clone();
+        match latest {
+            Some(snapshot) if snapshot.age_ms() < self.min_refresh_interval.as_millis() as u64 => {
+                snapshot
+            }
+            _ => self.refresh().await,
+        }
+    }
+
+    /// HTTP status `/health` answers with for `status`
+    pub fn status_code(&self, status: HealthStatus) -> StatusCode {
+        match status {
+            HealthStatus::Healthy => StatusCode::OK,
+            HealthStatus::Degraded => self.degraded_status_code,
+            HealthStatus::Unhealthy => self.unhealthy_status_code,
+        }
+    }
+
+    /// Recent status changes, oldest first
+    pub fn transitions(&self) -> Vec<HealthTransition> {
+        self.transitions.lock().unwrap().iter().cloned().collect()
+    }
+
+    fn record(&self, snapshot: &HealthSnapshot) {
+        let previous = self.latest.lock().unwrap().replace(snapshot.clone());
+        let Some(previous) = previous else {
+            return;
+        };
+
+        let at = snapshot.checked_at.to_rfc3339();
+        let mut changes = Vec::new();
+        if previous.status != snapshot.status {
+            changes.push(HealthTransition {
+                at: at.clone(),
+                component: "overall".to_string(),
+                from: previous.status,
+                to: snapshot.status,
+                error: None,
+            });
+        }
+        for component in &snapshot.components {
+            let before = previous
+                .components
+                .iter()
+                .find(|c| c.name == component.name)
+                .map(|c| c.status);
+            if let Some(before) = before
+                && before != component.status
+            {
+                changes.push(HealthTransition {
+                    at: at.clone(),
+                    component: component.name.clone(),
+                    from: before,
+                    to: component.status,
+                    error: component.error.clone(),
+                });
+            }
+        }
+
+        if changes.is_empty() {
+            return;
+        }
+        let mut transitions = self.transitions.lock().unwrap();
+        for change in changes {
+            info!(
+                component = change.component.as_str(),
+                from = change.from.as_str(),
+                to = change.to.as_str();
+                "Health changed"
+            );
+            transitions.push_back(change);
+        }
+        while transitions.len() > self.history_size {
+            transitions.pop_front();
+        }
+    }
+}
+
+fn status_code_or(code: u16, default: StatusCode) -> StatusCode {
+    StatusCode::from_u16(code).unwrap_or_else(|_| {
+        warn!(
+            code = code,
+            using = default.as_u16();
+            "Invalid health status code"
+        );
+        default
+    })
+}
+
+/// Round trip to the store through the worker pool
+pub struct DatabaseCheck {
+    db: DbExecutor,
+}
+
+impl DatabaseCheck {
+    pub fn new(db: DbExecutor) -> Self {
+        Self { db }
+    }
+}
+
+impl HealthCheck for DatabaseCheck {
+    fn name(&self) -> &'static str {
+        "database"
+    }
+
+    fn criticality(&self) -> Criticality {
+        Criticality::Critical
+    }
+
+    fn check(&self) -> BoxFuture<'_, CheckOutcome> {
+        Box::pin(async move {
+            match self.db.run("ping", |store| store.ping()).await {
+                Ok(()) => CheckOutcome::healthy(),
+                Err(e) => CheckOutcome::unhealthy(e.to_string()),
+            }
+        })
+    }
+}
+
+/// Startup load of the device cache
+pub struct CacheCheck {
+    cache: CacheWarmup,
+}
+
+impl CacheCheck {
+    pub fn new(cache: CacheWarmup) -> Self {
+        Self { cache }
+    }
+}
+
+impl HealthCheck for CacheCheck {
+    fn name(&self) -> &'static str {
+        "device_cache"
+    }
+
+    fn criticality(&self) -> Criticality {
+        Criticality::Critical
+    }
+
+    fn check(&self) -> BoxFuture<'_, CheckOutcome> {
+        Box::pin(async move {
+            let status = self.cache.status();
+            let details = json!({
+                "warmup": status.state,
+                "devices_loaded": status.devices_loaded,
+            });
+            let outcome = match status.state {
+                WarmupState::Ready => CheckOutcome::healthy(),
+                // Retried until it succeeds; only readiness waits for it
+                WarmupState::Failed => CheckOutcome::degraded(format!(
+                    "cache warmup failed: {}",
+                    status.last_error.unwrap_or_default()
+                ))
+                .not_ready(),
+                WarmupState::Pending | WarmupState::Running => {
+                    CheckOutcome::degraded("cache warmup in progress").not_ready()
+                }
+            };
+            outcome.with_details(details)
+        })
+    }
+}
+
+/// Backlog of the background heartbeat writer
+pub struct WriterQueueCheck {
+    writer: HeartbeatWriter,
+    degraded_percent: f64,
+}
+
+impl WriterQueueCheck {
+    pub fn new(writer: HeartbeatWriter, degraded_percent: f64) -> Self {
+        Self {
+            writer,
+            degraded_percent,
+        }
+    }
+}
+
+impl HealthCheck for WriterQueueCheck {
+    fn name(&self) -> &'static str {
+        "writer_queue"
+    }
+
+    fn criticality(&self) -> Criticality {
+        Criticality::Critical
+    }
+
+    fn check(&self) -> BoxFuture<'_, CheckOutcome> {
+        Box::pin(async move {
+            let stats = self.writer.stats();
+            let used_percent = 100.0 * stats.queued as f64 / stats.capacity.max(1) as f64;
+            let details = json!({
+                "queued": stats.queued,
+                "capacity": stats.capacity,
+                "failed": stats.failed,
+            });
+            let outcome = if !stats.running {
+                CheckOutcome::unhealthy("heartbeat writer stopped")
+            } else if stats.queued >= stats.capacity {
+                CheckOutcome::unhealthy(format!("queue full ({}/{})", stats.queued, stats.capacity))
+            } else if used_percent >= self.degraded_percent {
+                CheckOutcome::degraded(format!("queue {:.0}% full", used_percent))
+            } else {
+                CheckOutcome::healthy()
+            };
+            outcome.with_details(details)
+        })
+    }
+}
+
+/// Free space on the volume holding logs and the webhook dead-letter file
+pub struct DiskSpaceCheck {
+    path: PathBuf,
+    degraded_free_percent: f64,
+    unhealthy_free_percent: f64,
+}
+
+impl DiskSpaceCheck {
+    pub fn new(config: &HealthConfig) -> Self {
+        Self {
+            path: PathBuf::from(&config.disk_path),
+            degraded_free_percent: config.disk_degraded_free_percent,
+            unhealthy_free_percent: config.disk_unhealthy_free_percent,
+        }
+    }
+
+    /// (available, total) bytes of the disk whose mount point best matches `path`
+    fn space(path: &std::path::Path) -> Result<(u64, u64), String> {
+        let path = path
+            .canonicalize()
+            .map_err(|e| format!("cannot resolve {}: {}", path.display(), e))?;
+        let disks = sysinfo::Disks::new_with_refreshed_list();
+        disks
+            .list()
+            .iter()
+            .filter(|disk| path.starts_with(disk.mount_point()))
+            .max_by_key(|disk| disk.mount_point().as_os_str().len())
+            .map(|disk| (disk.available_space(), disk.total_space()))
+            .ok_or_else(|| format!("no disk found for {}", path.display()))
+    }
+}
+
+impl HealthCheck for DiskSpaceCheck {
+    fn name(&self) -> &'static str {
+        "disk_space"
+    }
+
+    fn criticality(&self) -> Criticality {
+        Criticality::NonCritical
+    }
+
+    fn check(&self) -> BoxFuture<'_, CheckOutcome> {
+        Box::pin(async move {
+            let path = self.path.clone();
+            let space = match tokio::task::spawn_blocking(move || Self::space(&path)).await {
+                Ok(space) => space,
+                Err(e) => Err(e.to_string()),
+            };
+            let (available, total) = match space {
+                Ok(space) => space,
+                Err(e) => return CheckOutcome::unhealthy(e),
+            };
+
+            let free_percent = 100.0 * available as f64 / total.max(1) as f64;
+            let details = json!({
+                "path": self.path.display().to_string(),
+                "available_bytes": available,
+                "total_bytes": total,
+                "free_percent": (free_percent * 100.0).round() / 100.0,
+            });
+            let outcome = if free_percent < self.unhealthy_free_percent {
+                CheckOutcome::unhealthy(format!("only {:.1}% disk space free", free_percent))
+            } else if free_percent < self.degraded_free_percent {
+                CheckOutcome::degraded(format!("only {:.1}% disk space free", free_percent))
+            } else {
+                CheckOutcome::healthy()
+            };
+            outcome.with_details(details)
+        })
+    }
+}
+
+/// Registry with the service's standard checks
+pub fn standard_checks(
+    config: &HealthConfig,
+    db: DbExecutor,
+    writer: HeartbeatWriter,
+    cache: CacheWarmup,
+) -> HealthRegistry {
+    let mut registry = HealthRegistry::new(Duration::from_millis(config.timeout_ms));
+    registry.register(DatabaseCheck::new(db));
+    registry.register(CacheCheck::new(cache));
+    registry.register(WriterQueueCheck::new(
+        writer,
+        config.writer_queue_degraded_percent,
+    ));
+    registry.register(DiskSpaceCheck::new(config));
+    registry
+}
@@ -0,0 +1,149 @@
+use axum::http::StatusCode;
+use chrono::{Duration, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::app::{ApiError, api_error, parse_time_param, store_error};
+use crate::server::AppState;
+use crate::store::{HeartbeatQuery, StoredHeartbeat};
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+const DEFAULT_LATEST: usize = 10;
+
+#[derive(Deserialize)]
+pub struct HistoryParams {
+    /// Start of the range (inclusive), defaults to 24 hours before `to`
+    pub from: Option<String>,
+    /// End of the range (exclusive), defaults to now
+    pub to: Option<String>,
+    /// `next_cursor` from the previous page
+    pub cursor: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct LatestParams {
+    pub n: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct HeartbeatPage {
+    pub mac: String,
+    pub from: String,
+    pub to: String,
+    pub count: usize,
+    pub heartbeats: Vec<StoredHeartbeat>,
+    /// Pass as `cursor` to fetch the next page; absent on the last page
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct LatestHeartbeats {
+    pub mac: String,
+    pub count: usize,
+    pub heartbeats: Vec<StoredHeartbeat>,
+}
+
+/// Business logic for reading heartbeat history back
+pub struct HistoryService;
+
+impl HistoryService {
+    /// Return one page of a device's heartbeats in a time range
+    pub async fn heartbeat_history(
+        state: &AppState,
+        mac: String,
+        params: HistoryParams,
+    ) -> Result<HeartbeatPage, ApiError> {
+        let to = match params.to.as_deref() {
+            Some(value) => parse_time_param("to", value)?,
+            None => Utc::now(),
+        };
+        let from = match params.from.as_deref() {
+            Some(value) => parse_time_param("from", value)?,
+            None => to - Duration::hours(24),
+        };
+        if from >= to {
+            return Err(api_error(StatusCode::BAD_REQUEST, "from must be before to"));
+        }
+
+        let after_id = match params.cursor.as_deref() {
+            Some(cursor) => Some(
+                cursor
+                    .parse::<u64>()
+                    .map_err(|_| api_error(StatusCode::BAD_REQUEST, "cursor is not valid"))?,
+            ),
+            None => None,
+        };
+        let limit = params
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+
+        info!(
+            mac = mac.as_str(),
+            from:% = from,
+            to:% = to,
+            after_id = after_id,
+            limit = limit;
+            "Heartbeat history requested"
+        );
+
+        // Ask for one extra row to learn whether there is another page
+        let query = HeartbeatQuery {
+            mac: mac.clone(),
+            from,
+            to,
+            after_id,
+            limit: limit + 1,
+        };
+        let mut heartbeats = state
+            .db
+            .run("heartbeat_history", move |store| {
+                store.heartbeat_history(&query)
+            })
+            .await
+            .map_err(store_error)?;
+
+        let next_cursor = if heartbeats.len() > limit {
+            heartbeats.truncate(limit);
+            heartbeats.last().map(|h| h.id.to_string())
+        } else {
+            None
+        };
+
+        Ok(HeartbeatPage {
+            mac,
+            from: from.to_rfc3339(),
+            to: to.to_rfc3339(),
+            count: heartbeats.len(),
+            heartbeats,
+            next_cursor,
+        })
+    }
+
+    /// Return a device's last `n` heartbeats, newest first
+    pub async fn latest_heartbeats(
+        state: &AppState,
+        mac: String,
+        params: LatestParams,
+    ) -> Result<LatestHeartbeats, ApiError> {
+        let limit = params.n.unwrap_or(DEFAULT_LATEST).clamp(1, MAX_PAGE_SIZE);
+        info!(mac = mac.as_str(), limit = limit; "Latest heartbeats requested");
+
+        let lookup_mac = mac.clone();
+        let heartbeats = state
+            .db
+            .run("latest_heartbeats", move |store| {
+                store.latest_heartbeats(&lookup_mac, limit)
+            })
+            .await
+            .map_err(store_error)?;
+
+        Ok(LatestHeartbeats {
+            mac,
+            count: heartbeats.len(),
+            heartbeats,
+        })
+    }
+}
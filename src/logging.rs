@@ -0,0 +1,986 @@
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::{DateTime, Utc};
+use log::kv::{self, VisitSource};
+use log::{LevelFilter, Record, error, info, warn};
+use log4rs::Handle;
+use log4rs::append::console::{ConsoleAppender, Target};
+use log4rs::append::rolling_file::LogFile;
+use log4rs::append::rolling_file::RollingFileAppender;
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::policy::compound::roll::Roll;
+use log4rs::append::rolling_file::policy::compound::roll::delete::DeleteRoller;
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::trigger::Trigger;
+use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
+use log4rs::append::rolling_file::policy::compound::trigger::time::{
+    TimeTrigger, TimeTriggerConfig,
+};
+use log4rs::config::runtime::ConfigBuilder;
+use log4rs::config::{Appender, Config, Deserializers, Logger, RawConfig, Root};
+use log4rs::encode::pattern::PatternEncoder;
+use log4rs::encode::{Encode, Write};
+use log4rs::filter::{Filter, Response as FilterResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::{self as app_config, FileLogConfig, LogFormat};
+use crate::request_id;
+
+const PATTERN: &str = "{d(%Y-%m-%d %H:%M:%S)} [{h({l})}] {t} [{X(request_id)(-)}] - {m}{n}";
+
+/// Targets under this prefix can be made verbose for a single device
+const CRATE_TARGET: &str = "axum_health_service";
+
+/// MDC key holding the MAC of a heartbeat being debugged
+pub const DEBUG_MAC_KEY: &str = "debug_mac";
+
+/// Set up log4rs from the `[app]` level and the `[logging]` section
+///
+/// An external log4rs file named by `logging.config_file` takes over when
+/// it loads, and is watched for changes. Console output goes to stderr
+/// when stdout carries command output; CLI commands always use the
+/// built-in configuration so the file cannot send log lines to stdout.
+pub fn init(config: &app_config::Config, to_stderr: bool) -> Result<LogControl> {
+    let mut levels = BTreeMap::new();
+    for (target, level) in &config.logging.levels {
+        levels.insert(target.clone(), parse_level(level)?);
+    }
+    let settings = Settings {
+        format: config.logging.format,
+        console: config.logging.console,
+        to_stderr,
+        file: config.logging.file.clone(),
+        levels: EffectiveLevels {
+            root: parse_level(&config.app.log_level)?,
+            targets: levels,
+        },
+    };
+    let config_file = config
+        .logging
+        .config_file
+        .as_ref()
+        .filter(|_| !to_stderr)
+        .map(PathBuf::from);
+
+    // Why the external file was not used, logged once log4rs is up
+    let mut fallback_reason = None;
+    let mut source = None;
+    let mut external = None;
+    if let Some(path) = &config_file {
+        match fs::read_to_string(path) {
+            Ok(text) => {
+                match load_file_config(path, &text) {
+                    Ok(loaded) => external = Some(loaded),
+                    Err(e) => fallback_reason = Some(format!("{:#}", e)),
+                }
+                source = Some(text);
+            }
+            Err(e) => fallback_reason = Some(e.to_string()),
+        }
+    }
+
+    let from_file = external.and_then(|external| {
+        let runtime = Runtime {
+            external: Some(external),
+            ..Runtime::default()
+        };
+        match build_config(&settings, &runtime) {
+            Ok(log4rs_config) => Some((log4rs_config, runtime)),
+            Err(e) => {
+                fallback_reason = Some(format!("{:#}", e));
+                None
+            }
+        }
+    });
+    let (log4rs_config, runtime) = match from_file {
+        Some(loaded) => loaded,
+        None => (
+            build_config(&settings, &Runtime::default())?,
+            Runtime::default(),
+        ),
+    };
+    let handle = log4rs::init_config(log4rs_config)?;
+    let control = LogControl {
+        inner: Arc::new(ControlInner {
+            handle,
+            settings,
+            config_file: config_file.clone(),
+            runtime: Mutex::new(runtime),
+        }),
+    };
+
+    if let Some(path) = config_file {
+        match fallback_reason {
+            Some(reason) => warn!(
+                path:% = path.display(),
+                reason = reason.as_str();
+                "Logging config file not used, falling back to the built-in configuration"
+            ),
+            None => info!(path:% = path.display(); "Logging configured from file"),
+        }
+        if config.logging.config_refresh_seconds > 0 {
+            watch_config_file(
+                control.clone(),
+                path,
+                source,
+                Duration::from_secs(config.logging.config_refresh_seconds),
+            );
+        }
+    }
+    Ok(control)
+}
+
+/// What `config.toml` asked for, kept so runtime changes can be undone
+struct Settings {
+    format: LogFormat,
+    console: bool,
+    to_stderr: bool,
+    file: FileLogConfig,
+    levels: EffectiveLevels,
+}
+
+/// Changes made while running, through the admin endpoints or the config file
+#[derive(Clone, Default)]
+struct Runtime {
+    /// Keyed by target; `None` is the root level
+    overrides: BTreeMap<Option<String>, LevelOverride>,
+    debug_macs: BTreeSet<String>,
+    debug_macs_expire_at: Option<DateTime<Utc>>,
+    /// The external log4rs file, while it is the one in force
+    external: Option<ExternalConfig>,
+}
+
+/// An external log4rs file, kept so runtime changes can be layered on top
+#[derive(Clone)]
+struct ExternalConfig {
+    /// The file as a generic value, so filters can be added to its appenders
+    source: Value,
+    levels: EffectiveLevels,
+}
+
+#[derive(Clone)]
+struct LevelOverride {
+    level: LevelFilter,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+/// Root level plus per-target levels, resolved like log4rs does
+#[derive(Clone, Debug)]
+struct EffectiveLevels {
+    root: LevelFilter,
+    targets: BTreeMap<String, LevelFilter>,
+}
+
+impl EffectiveLevels {
+    /// Level of the longest configured target that `target` falls under
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .filter(|(name, _)| is_under(target, name))
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.root)
+    }
+}
+
+fn is_under(target: &str, parent: &str) -> bool {
+    target == parent || (target.starts_with(parent) && target[parent.len()..].starts_with("::"))
+}
+
+/// The external file's levels, or else config.toml's, with runtime overrides applied
+fn effective_levels(settings: &Settings, runtime: &Runtime) -> EffectiveLevels {
+    let mut levels = match &runtime.external {
+        Some(external) => external.levels.clone(),
+        None => settings.levels.clone(),
+    };
+    for (target, change) in &runtime.overrides {
+        match target {
+            Some(target) => {
+                levels.targets.insert(target.clone(), change.level);
+            }
+            None => levels.root = change.level,
+        }
+    }
+    levels
+}
+
+fn build_config(settings: &Settings, runtime: &Runtime) -> Result<Config> {
+    let levels = effective_levels(settings, runtime);
+    // Debugging devices lets debug records through this crate's loggers and
+    // leaves it to the filter to drop those not written for one of the devices
+    let debug_filter = (!runtime.debug_macs.is_empty()).then(|| MacDebugFilter {
+        normal: levels.clone(),
+    });
+    let raise = |target: &str, level: LevelFilter| {
+        if debug_filter.is_some() && is_under(target, CRATE_TARGET) {
+            level.max(LevelFilter::Debug)
+        } else {
+            level
+        }
+    };
+
+    let (mut builder, appenders, file_loggers) = match &runtime.external {
+        Some(external) => file_appenders(external, debug_filter.as_ref())?,
+        None => {
+            let (builder, appenders) = built_in_appenders(settings, debug_filter.as_ref())?;
+            (builder, appenders, Vec::new())
+        }
+    };
+    for (target, level) in &levels.targets {
+        let mut logger = Logger::builder();
+        // The file's own loggers keep their appenders and additivity
+        if let Some(file_logger) = file_loggers.iter().find(|logger| logger.name() == target) {
+            logger = logger
+                .appenders(file_logger.appenders().iter().cloned())
+                .additive(file_logger.additive());
+        }
+        builder = builder.logger(logger.build(target, raise(target, *level)));
+    }
+    if debug_filter.is_some() && !levels.targets.contains_key(CRATE_TARGET) {
+        let level = raise(CRATE_TARGET, levels.level_for(CRATE_TARGET));
+        builder = builder.logger(Logger::builder().build(CRATE_TARGET, level));
+    }
+    Ok(builder.build(Root::builder().appenders(appenders).build(levels.root))?)
+}
+
+/// The console and file appenders configured in `[logging]`, with their root appender names
+fn built_in_appenders(
+    settings: &Settings,
+    debug_filter: Option<&MacDebugFilter>,
+) -> Result<(ConfigBuilder, Vec<String>)> {
+    let mut builder = Config::builder();
+    let mut appenders = Vec::new();
+    let appender = |filter: Option<&MacDebugFilter>| {
+        let mut appender = Appender::builder();
+        if let Some(filter) = filter {
+            appender = appender.filter(Box::new(filter.clone()));
+        }
+        appender
+    };
+
+    if settings.console {
+        let console = ConsoleAppender::builder()
+            .encoder(encoder(settings.format))
+            .target(if settings.to_stderr {
+                Target::Stderr
+            } else {
+                Target::Stdout
+            })
+            .build();
+        builder = builder.appender(appender(debug_filter).build("console", Box::new(console)));
+        appenders.push("console".to_string());
+    }
+    if settings.file.enabled {
+        let file = rolling_file(&settings.file, settings.format)?;
+        builder = builder.appender(appender(debug_filter).build("file", Box::new(file)));
+        appenders.push("file".to_string());
+    }
+    Ok((builder, appenders))
+}
+
+/// The external file's appenders, root appender names, and loggers
+///
+/// While devices are debugged, every appender gets a `kind: mac_debug`
+/// filter after any filters the file already has.
+fn file_appenders(
+    external: &ExternalConfig,
+    debug_filter: Option<&MacDebugFilter>,
+) -> Result<(ConfigBuilder, Vec<String>, Vec<Logger>)> {
+    let mut source = external.source.clone();
+    let mut deserializers = deserializers();
+    if let Some(filter) = debug_filter {
+        deserializers.insert("mac_debug", MacDebugFilterDeserializer(filter.clone()));
+        if let Some(Value::Object(appenders)) = source.get_mut("appenders") {
+            for appender in appenders.values_mut().filter_map(Value::as_object_mut) {
+                if let Value::Array(filters) = appender
+                    .entry("filters")
+                    .or_insert_with(|| Value::Array(Vec::new()))
+                {
+                    filters.push(serde_json::json!({ "kind": "mac_debug" }));
+                }
+            }
+        }
+    }
+    let raw: RawConfig = serde_json::from_value(source)?;
+    let (appenders, errors) = raw.appenders_lossy(&deserializers);
+    if !errors.is_empty() {
+        return Err(anyhow::Error::new(errors));
+    }
+    Ok((
+        Config::builder().appenders(appenders),
+        raw.root().appenders().to_vec(),
+        raw.loggers(),
+    ))
+}
+
+/// Handle for changing log levels while the service runs
+///
+/// Every change rebuilds the log4rs config from `config.toml`, or the
+/// external file while one is in force, plus the current overrides, so
+/// reverting one change never loses another.
+#[derive(Clone)]
+pub struct LogControl {
+    inner: Arc<ControlInner>,
+}
+
+struct ControlInner {
+    handle: Handle,
+    settings: Settings,
+    config_file: Option<PathBuf>,
+    runtime: Mutex<Runtime>,
+}
+
+/// Current levels and runtime changes, as reported by the admin endpoint
+#[derive(Debug, Serialize)]
+pub struct LoggingStatus {
+    /// External log4rs file setting the levels, if one is in force
+    pub config_file: Option<String>,
+    pub root: String,
+    /// Every target with its own level, after overrides
+    pub levels: BTreeMap<String, String>,
+    pub overrides: Vec<LevelOverrideStatus>,
+    pub debug_macs: Vec<String>,
+    pub debug_macs_expire_at: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LevelOverrideStatus {
+    /// `None` for the root level
+    pub target: Option<String>,
+    pub level: String,
+    pub expires_at: Option<String>,
+}
+
+impl LogControl {
+    pub fn status(&self) -> LoggingStatus {
+        let runtime = self.inner.runtime.lock().unwrap();
+        let levels = effective_levels(&self.inner.settings, &runtime);
+        LoggingStatus {
+            config_file: runtime
+                .external
+                .as_ref()
+                .and(self.inner.config_file.as_ref())
+                .map(|path| path.display().to_string()),
+            root: levels.root.to_string(),
+            levels: levels
+                .targets
+                .iter()
+                .map(|(target, level)| (target.clone(), level.to_string()))
+                .collect(),
+            overrides: runtime
+                .overrides
+                .iter()
+                .map(|(target, change)| LevelOverrideStatus {
+                    target: target.clone(),
+                    level: change.level.to_string(),
+                    expires_at: change.expires_at.map(|at| at.to_rfc3339()),
+                })
+                .collect(),
+            debug_macs: runtime.debug_macs.iter().cloned().collect(),
+            debug_macs_expire_at: runtime.debug_macs_expire_at.map(|at| at.to_rfc3339()),
+        }
+    }
+
+    /// Set the level of `target` (the root when `None`), reverting after `revert_after`
+    pub fn set_level(
+        &self,
+        target: Option<String>,
+        level: LevelFilter,
+        revert_after: Option<Duration>,
+    ) -> Result<()> {
+        let expires_at = revert_after.map(|after| Utc::now() + after);
+        self.update(|runtime| {
+            runtime
+                .overrides
+                .insert(target.clone(), LevelOverride { level, expires_at });
+        })?;
+        warn!(
+            log_target = target.as_deref().unwrap_or("root"),
+            level:% = level,
+            revert_after_secs = revert_after.map(|after| after.as_secs());
+            "Log level changed at runtime"
+        );
+        self.schedule_expiry(revert_after);
+        Ok(())
+    }
+
+    /// Go back to the configured level for `target`
+    pub fn reset_level(&self, target: Option<String>) -> Result<()> {
+        self.update(|runtime| {
+            runtime.overrides.remove(&target);
+        })?;
+        info!(log_target = target.as_deref().unwrap_or("root"); "Log level reset");
+        Ok(())
+    }
+
+    /// Log at debug for heartbeats from `macs` only, until `revert_after`
+    pub fn set_debug_macs(&self, macs: Vec<String>, revert_after: Option<Duration>) -> Result<()> {
+        let expires_at = revert_after.map(|after| Utc::now() + after);
+        let macs: BTreeSet<String> = macs.iter().map(|mac| mac.to_ascii_lowercase()).collect();
+        let listed = macs.iter().cloned().collect::<Vec<_>>().join(",");
+        self.update(|runtime| {
+            runtime.debug_macs = macs;
+            runtime.debug_macs_expire_at = expires_at;
+        })?;
+        warn!(
+            macs = listed,
+            revert_after_secs = revert_after.map(|after| after.as_secs());
+            "Debug logging enabled for devices"
+        );
+        self.schedule_expiry(revert_after);
+        Ok(())
+    }
+
+    pub fn clear_debug_macs(&self) -> Result<()> {
+        self.update(|runtime| {
+            runtime.debug_macs.clear();
+            runtime.debug_macs_expire_at = None;
+        })?;
+        info!("Debug logging for devices disabled");
+        Ok(())
+    }
+
+    pub fn is_debug_mac(&self, mac: &str) -> bool {
+        let runtime = self.inner.runtime.lock().unwrap();
+        !runtime.debug_macs.is_empty() && runtime.debug_macs.contains(&mac.to_ascii_lowercase())
+    }
+
+    /// The external log4rs file, while it rather than `config.toml` is in force
+    pub fn config_file(&self) -> Option<String> {
+        let runtime = self.inner.runtime.lock().unwrap();
+        runtime
+            .external
+            .as_ref()
+            .and(self.inner.config_file.as_ref())
+            .map(|path| path.display().to_string())
+    }
+
+    /// Apply `change` and reload log4rs; nothing is kept if the reload fails
+    fn update(&self, change: impl FnOnce(&mut Runtime)) -> Result<()> {
+        let mut runtime = self.inner.runtime.lock().unwrap();
+        let mut updated = runtime.clone();
+        change(&mut updated);
+        let config = build_config(&self.inner.settings, &updated)?;
+        self.inner.handle.set_config(config);
+        *runtime = updated;
+        Ok(())
+    }
+
+    fn schedule_expiry(&self, after: Option<Duration>) {
+        let Some(after) = after else { return };
+        let control = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(after).await;
+            control.expire();
+        });
+    }
+
+    /// Drop every change whose time is up
+    fn expire(&self) {
+        let now = Utc::now();
+        let expired = |at: Option<DateTime<Utc>>| at.is_some_and(|at| at <= now);
+        let mut reverted = Vec::new();
+        let mut debug_expired = false;
+        let result = self.update(|runtime| {
+            runtime.overrides.retain(|target, change| {
+                let keep = !expired(change.expires_at);
+                if !keep {
+                    reverted.push(target.clone().unwrap_or_else(|| "root".to_string()));
+                }
+                keep
+            });
+            if expired(runtime.debug_macs_expire_at) {
+                debug_expired = true;
+                runtime.debug_macs.clear();
+                runtime.debug_macs_expire_at = None;
+            }
+        });
+        // Logged after the reload so a temporarily quieter level cannot hide it
+        match result {
+            Ok(()) => {
+                for target in reverted {
+                    info!(log_target = target.as_str(); "Log level override expired");
+                }
+                if debug_expired {
+                    info!("Debug logging for devices expired");
+                }
+            }
+            Err(e) => error!(error:% = e; "Failed to revert log levels"),
+        }
+    }
+
+    /// Switch to the external file, or keep the current config if it is invalid
+    ///
+    /// Runtime level changes and debugged devices carry over to the new file.
+    fn apply_file(&self, path: &Path, source: &str) {
+        let result = load_file_config(path, source)
+            .and_then(|external| self.update(|runtime| runtime.external = Some(external)));
+        match result {
+            Ok(()) => info!(path:% = path.display(); "Logging config file reloaded"),
+            Err(e) => warn!(
+                path:% = path.display(),
+                error = format!("{:#}", e);
+                "Ignoring invalid logging config file, keeping the current configuration"
+            ),
+        }
+    }
+
+    /// Go back to the built-in config once the external file is gone
+    fn file_removed(&self, path: &Path) {
+        if self.config_file().is_none() {
+            return;
+        }
+        match self.update(|runtime| runtime.external = None) {
+            Ok(()) => {
+                warn!(
+                    path:% = path.display();
+                    "Logging config file removed, falling back to the built-in configuration"
+                );
+            }
+            Err(e) => {
+                error!(error = format!("{:#}", e); "Failed to restore the built-in logging configuration")
+            }
+        }
+    }
+}
+
+/// Check the external log4rs file every `every` and apply it when it changes
+///
+/// `last` is the content already dealt with at startup, so an unchanged
+/// invalid file is only reported once.
+fn watch_config_file(
+    control: LogControl,
+    path: PathBuf,
+    mut last: Option<String>,
+    every: Duration,
+) {
+    let spawned = std::thread::Builder::new()
+        .name("log-config-watcher".to_string())
+        .spawn(move || {
+            loop {
+                std::thread::sleep(every);
+                let source = match fs::read_to_string(&path) {
+                    Ok(source) => Some(source),
+                    Err(e) if e.kind() == ErrorKind::NotFound => None,
+                    Err(e) => {
+                        warn!(path:% = path.display(), error:% = e; "Failed to read logging config file");
+                        continue;
+                    }
+                };
+                if source == last {
+                    continue;
+                }
+                match &source {
+                    Some(source) => control.apply_file(&path, source),
+                    None => control.file_removed(&path),
+                }
+                last = source;
+            }
+        });
+    if let Err(e) = spawned {
+        error!(error:% = e; "Failed to start the logging config file watcher");
+    }
+}
+
+/// Parse a log4rs `.yaml`, `.toml`, or `.json` file
+///
+/// Its appenders are only built by `build_config`, which fails on any bad
+/// component; log4rs's own loader skips appenders it cannot build, while a
+/// file that only half works is rejected so the previous config stays in force.
+fn load_file_config(path: &Path, source: &str) -> Result<ExternalConfig> {
+    let source: Value = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("yaml") | Some("yml") => serde_yaml::from_str(source)?,
+        Some("toml") => toml::from_str(source)?,
+        Some("json") => serde_json::from_str(source)?,
+        _ => bail!("unsupported logging config file type, expected .yaml, .toml, or .json"),
+    };
+    let raw: RawConfig = serde_json::from_value(source.clone())?;
+    let levels = EffectiveLevels {
+        root: raw.root().level(),
+        targets: raw
+            .loggers()
+            .iter()
+            .map(|logger| (logger.name().to_string(), logger.level()))
+            .collect(),
+    };
+    Ok(ExternalConfig { source, levels })
+}
+
+/// The stock log4rs components plus this service's encoders, as
+/// `kind: text` (with an optional `pattern`) and `kind: json_lines`
+fn deserializers() -> Deserializers {
+    let mut deserializers = Deserializers::default();
+    deserializers.insert("text", TextEncoderDeserializer);
+    deserializers.insert("json_lines", JsonEncoderDeserializer);
+    deserializers
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TextEncoderConfig {
+    pattern: Option<String>,
+}
+
+struct TextEncoderDeserializer;
+
+impl log4rs::config::Deserialize for TextEncoderDeserializer {
+    type Trait = dyn Encode;
+    type Config = TextEncoderConfig;
+
+    fn deserialize(
+        &self,
+        config: TextEncoderConfig,
+        _: &Deserializers,
+    ) -> anyhow::Result<Box<dyn Encode>> {
+        Ok(Box::new(TextEncoder {
+            pattern: PatternEncoder::new(config.pattern.as_deref().unwrap_or(PATTERN)),
+        }))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonEncoderConfig {}
+
+struct JsonEncoderDeserializer;
+
+impl log4rs::config::Deserialize for JsonEncoderDeserializer {
+    type Trait = dyn Encode;
+    type Config = JsonEncoderConfig;
+
+    fn deserialize(
+        &self,
+        _: JsonEncoderConfig,
+        _: &Deserializers,
+    ) -> anyhow::Result<Box<dyn Encode>> {
+        Ok(Box::new(JsonEncoder))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MacDebugFilterConfig {}
+
+/// Builds the filter added to an external file's appenders while devices are debugged
+struct MacDebugFilterDeserializer(MacDebugFilter);
+
+impl log4rs::config::Deserialize for MacDebugFilterDeserializer {
+    type Trait = dyn Filter;
+    type Config = MacDebugFilterConfig;
+
+    fn deserialize(
+        &self,
+        _: MacDebugFilterConfig,
+        _: &Deserializers,
+    ) -> anyhow::Result<Box<dyn Filter>> {
+        Ok(Box::new(self.0.clone()))
+    }
+}
+
+/// Passes records the configured levels allow, plus verbose records from
+/// this crate written while handling a heartbeat from a debugged device
+#[derive(Clone, Debug)]
+struct MacDebugFilter {
+    normal: EffectiveLevels,
+}
+
+impl Filter for MacDebugFilter {
+    fn filter(&self, record: &Record) -> FilterResponse {
+        if record.level() <= self.normal.level_for(record.target())
+            || (is_under(record.target(), CRATE_TARGET)
+                && log_mdc::get(DEBUG_MAC_KEY, |value| value.is_some()))
+        {
+            FilterResponse::Neutral
+        } else {
+            FilterResponse::Reject
+        }
+    }
+}
+
+fn encoder(format: LogFormat) -> Box<dyn Encode> {
+    match format {
+        LogFormat::Text => Box::new(TextEncoder {
+            pattern: PatternEncoder::new(PATTERN),
+        }),
+        LogFormat::Json => Box::new(JsonEncoder),
+    }
+}
+
+pub fn parse_level(level: &str) -> Result<LevelFilter> {
+    level.parse().map_err(|_| {
+        anyhow!(
+            "invalid log level '{}', expected off, error, warn, info, debug, or trace",
+            level
+        )
+    })
+}
+
+/// File appender that rolls on size, time, or both, keeping `keep` old files
+fn rolling_file(config: &FileLogConfig, format: LogFormat) -> Result<RollingFileAppender> {
+    let mut triggers: Vec<Box<dyn Trigger>> = Vec::new();
+    if let Some(max_size_mb) = config.max_size_mb {
+        triggers.push(Box::new(SizeTrigger::new(max_size_mb * 1024 * 1024)));
+    }
+    if let Some(interval) = &config.roll_interval {
+        // The time trigger's settings can only be built by deserializing them
+        let time_config: TimeTriggerConfig =
+            serde_json::from_value(serde_json::json!({ "interval": interval }))
+                .with_context(|| format!("invalid logging.file.roll_interval '{}'", interval))?;
+        triggers.push(Box::new(TimeTrigger::new(time_config)));
+    }
+    if triggers.is_empty() {
+        bail!("logging.file needs max_size_mb, roll_interval, or both");
+    }
+
+    let roller: Box<dyn Roll> = if config.keep == 0 {
+        Box::new(DeleteRoller::new())
+    } else {
+        let suffix = if config.compress { ".gz" } else { "" };
+        let pattern = format!("{}.{{}}{}", config.path, suffix);
+        Box::new(
+            FixedWindowRoller::builder()
+                .base(1)
+                .build(&pattern, config.keep)?,
+        )
+    };
+
+    let policy = CompoundPolicy::new(Box::new(AnyTrigger { triggers }), roller);
+    RollingFileAppender::builder()
+        .encoder(encoder(format))
+        .build(&config.path, Box::new(policy))
+        .with_context(|| format!("failed to open log file {}", config.path))
+}
+
+/// Rolls when any of its triggers fires
+///
+/// log4rs policies take a single trigger, so size and time limits are
+/// combined here. Every trigger is checked on each record so the time
+/// trigger keeps its schedule even when the size trigger fired.
+#[derive(Debug)]
+struct AnyTrigger {
+    triggers: Vec<Box<dyn Trigger>>,
+}
+
+impl Trigger for AnyTrigger {
+    fn trigger(&self, file: &LogFile) -> anyhow::Result<bool> {
+        let mut fired = false;
+        for trigger in &self.triggers {
+            fired |= trigger.trigger(file)?;
+        }
+        Ok(fired)
+    }
+
+    /// Checked before each write, which time triggers need and size
+    /// triggers tolerate by overshooting the limit by one record
+    fn is_pre_process(&self) -> bool {
+        self.triggers.iter().any(|trigger| trigger.is_pre_process())
+    }
+}
+
+/// The usual pattern, with the record's key-values appended as `key=value`
+#[derive(Debug)]
+struct TextEncoder {
+    pattern: PatternEncoder,
+}
+
+impl Encode for TextEncoder {
+    fn encode(&self, w: &mut dyn Write, record: &Record) -> anyhow::Result<()> {
+        let mut fields = TextFields(String::new());
+        record.key_values().visit(&mut fields)?;
+        if fields.0.is_empty() {
+            return self.pattern.encode(w, record);
+        }
+        self.pattern.encode(
+            w,
+            &record
+                .to_builder()
+                .args(format_args!("{}{}", record.args(), fields.0))
+                .build(),
+        )
+    }
+}
+
+struct TextFields(String);
+
+impl<'kvs> VisitSource<'kvs> for TextFields {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = value.to_string();
+        if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"') {
+            let _ = write!(self.0, " {}={:?}", key, value);
+        } else {
+            let _ = write!(self.0, " {}={}", key, value);
+        }
+        Ok(())
+    }
+}
+
+/// One JSON object per line, with the record's key-values as top-level keys
+///
+/// `timestamp`, `level`, `target`, `thread`, `message`, and `request_id`
+/// are always written by the encoder and win over a key-value of the same name.
+#[derive(Debug)]
+struct JsonEncoder;
+
+impl Encode for JsonEncoder {
+    fn encode(&self, w: &mut dyn Write, record: &Record) -> anyhow::Result<()> {
+        let mut fields = JsonFields(Map::new());
+        record.key_values().visit(&mut fields)?;
+        let mut event = fields.0;
+
+        let thread = std::thread::current();
+        event.insert(
+            "timestamp".to_string(),
+            Value::String(
+                chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+            ),
+        );
+        event.insert(
+            "level".to_string(),
+            Value::String(record.level().to_string()),
+        );
+        event.insert(
+            "target".to_string(),
+            Value::String(record.target().to_string()),
+        );
+        event.insert(
+            "thread".to_string(),
+            Value::String(
+                thread
+                    .name()
+                    .map(str::to_string)
+                    .unwrap_or_else(|| format!("{:?}", thread.id())),
+            ),
+        );
+        event.insert(
+            "message".to_string(),
+            Value::String(record.args().to_string()),
+        );
+        if let Some(id) = request_id::current() {
+            event.insert("request_id".to_string(), Value::String(id));
+        }
+
+        serde_json::to_writer(&mut *w, &event)?;
+        w.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+struct JsonFields(Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value =
+            serde_json::to_value(&value).unwrap_or_else(|_| Value::String(value.to_string()));
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = r#"{
+        "appenders": {
+            "stdout": {
+                "kind": "console",
+                "encoder": { "kind": "text" },
+                "filters": [{ "kind": "threshold", "level": "trace" }]
+            }
+        },
+        "root": { "level": "warn", "appenders": ["stdout"] },
+        "loggers": {
+            "mysql": { "level": "error", "appenders": ["stdout"], "additive": false }
+        }
+    }"#;
+
+    fn settings() -> Settings {
+        Settings {
+            format: LogFormat::Text,
+            console: true,
+            to_stderr: true,
+            file: FileLogConfig::default(),
+            levels: EffectiveLevels {
+                root: LevelFilter::Info,
+                targets: BTreeMap::new(),
+            },
+        }
+    }
+
+    fn with_file() -> Runtime {
+        Runtime {
+            external: Some(load_file_config(Path::new("log4rs.json"), FILE).unwrap()),
+            ..Runtime::default()
+        }
+    }
+
+    fn logger<'a>(config: &'a Config, name: &str) -> &'a Logger {
+        config
+            .loggers()
+            .iter()
+            .find(|logger| logger.name() == name)
+            .unwrap()
+    }
+
+    #[test]
+    fn overrides_are_layered_on_the_file() {
+        let mut runtime = with_file();
+        for (target, level) in [
+            (None, LevelFilter::Info),
+            (Some("mysql"), LevelFilter::Debug),
+        ] {
+            runtime.overrides.insert(
+                target.map(str::to_string),
+                LevelOverride {
+                    level,
+                    expires_at: None,
+                },
+            );
+        }
+
+        let config = build_config(&settings(), &runtime).unwrap();
+
+        assert_eq!(config.root().level(), LevelFilter::Info);
+        assert_eq!(config.root().appenders(), ["stdout"]);
+        let mysql = logger(&config, "mysql");
+        assert_eq!(mysql.level(), LevelFilter::Debug);
+        assert_eq!(mysql.appenders(), ["stdout"]);
+        assert!(!mysql.additive());
+    }
+
+    #[test]
+    fn debugging_devices_filters_file_appenders_and_raises_only_this_crate() {
+        let mut runtime = with_file();
+        runtime.debug_macs.insert("00:11:22:33:44:55".to_string());
+
+        let config = build_config(&settings(), &runtime).unwrap();
+
+        assert_eq!(config.appenders()[0].filters().len(), 2);
+        assert_eq!(config.root().level(), LevelFilter::Warn);
+        assert_eq!(logger(&config, "mysql").level(), LevelFilter::Error);
+        assert_eq!(logger(&config, CRATE_TARGET).level(), LevelFilter::Debug);
+    }
+
+    #[test]
+    fn invalid_file_appender_is_rejected() {
+        let runtime = Runtime {
+            external: Some(
+                load_file_config(
+                    Path::new("log4rs.yaml"),
+                    "appenders:\n  out:\n    kind: nope\nroot:\n  level: info\n",
+                )
+                .unwrap(),
+            ),
+            ..Runtime::default()
+        };
+
+        assert!(build_config(&settings(), &runtime).is_err());
+    }
+}
@@ -1,40 +1,171 @@
-use log::{error, info};
-use mysql::prelude::Queryable;
-use mysql::{OptsBuilder, Pool};
+use log::{error, info, warn};
+use mysql::{OptsBuilder, Pool, PoolConstraints, PoolOpts};
 use std::net::SocketAddr;
-use tokio;
+use std::sync::Arc;
+use std::time::Duration;
 
+mod admin;
 mod app;
+mod breaker;
 mod config;
+mod executor;
+mod export;
+mod health;
+mod history;
+mod logging;
+mod mdc;
+mod metrics;
+mod migrations;
+mod reports;
+mod request_id;
+mod retention;
 mod server;
+mod statsd;
+mod store;
+mod webhooks;
+mod writer;
 
-fn init_logging() -> Result<(), Box<dyn std::error::Error>> {
-    use log4rs::{
-        append::console::ConsoleAppender,
-        config::{Appender, Config, Root},
-        encode::pattern::PatternEncoder,
+/// Create the MySQL connection pool and wrap it in a `DeviceStore`
+///
+/// `migrations` overrides `database.migrations`, so commands other than the
+/// server can check the schema without changing it.
+fn create_mysql_store(
+    config: &config::Config,
+    migrations: config::MigrationMode,
+) -> Arc<dyn store::DeviceStore> {
+    info!("Database URL: {}", config.database_url());
+
+    let database = &config.database;
+    let timeout = Duration::from_secs(database.timeout_seconds);
+
+    let constraints =
+        match PoolConstraints::new(database.min_idle as usize, database.pool_size as usize) {
+            Some(constraints) => constraints,
+            None => {
+                error!(
+                    "Invalid pool constraints: min_idle ({}) exceeds pool_size ({})",
+                    database.min_idle, database.pool_size
+                );
+                std::process::exit(1);
+            }
+        };
+
+    let db_opts = OptsBuilder::new()
+        .ip_or_hostname(Some(&database.host))
+        .tcp_port(database.port)
+        .user(Some(&database.username))
+        .pass(Some(&database.password))
+        .db_name(Some(&database.database))
+        .pool_opts(PoolOpts::default().with_constraints(constraints))
+        .tcp_connect_timeout(Some(timeout))
+        .read_timeout(Some(timeout))
+        .write_timeout(Some(timeout))
+        .tcp_keepalive_time_ms(database.tcp_keepalive_ms())
+        .stmt_cache_size(database.stmt_cache_size);
+
+    match Pool::new(db_opts) {
+        Ok(pool) => {
+            info!("Database connection pool created successfully");
+            info!(
+                "Pool size: {} (min idle {}), timeout: {}s, keepalive: {}s, statement cache: {}",
+                database.pool_size,
+                database.min_idle,
+                database.timeout_seconds,
+                database.tcp_keepalive_seconds,
+                database.stmt_cache_size
+            );
+            if let Err(e) = migrations::run(&pool, migrations) {
+                error!("Database schema check failed: {:#}", e);
+                std::process::exit(1);
+            }
+            let limits = store::PoolLimits {
+                min_idle: database.min_idle as usize,
+                max_connections: database.pool_size as usize,
+                acquire_timeout: timeout,
+            };
+            Arc::new(store::MySqlDeviceStore::new(pool, limits))
+        }
+        Err(e) => {
+            error!("Failed to create database connection pool: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Create an in-memory `DeviceStore` seeded from `database.memory_devices`
+fn create_memory_store(config: &config::Config) -> Arc<dyn store::DeviceStore> {
+    warn!("Using in-memory device store; heartbeats will not survive a restart");
+
+    let store = store::InMemoryDeviceStore::new();
+    for mac in &config.database.memory_devices {
+        store.register_device(mac, None, false);
+    }
+    info!(
+        "Registered {} in-memory devices",
+        config.database.memory_devices.len()
+    );
+    Arc::new(store)
+}
+
+/// Run the `export` subcommand against the configured store and exit
+fn run_export(config: &config::Config, args: &[String]) {
+    let command = match export::ExportCommand::parse(args) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    // An export only reads, so it never takes the migration lock or changes the schema
+    let migrations = match config.database.migrations {
+        config::MigrationMode::Apply => config::MigrationMode::Verify,
+        mode => mode,
     };
+    let store = match config.database.backend {
+        config::StoreBackend::Mysql => create_mysql_store(config, migrations),
+        config::StoreBackend::Memory => create_memory_store(config),
+    };
+
+    if let Err(e) = command.run(store.as_ref()) {
+        error!("Export failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Serve the admin endpoints on their own listener in the background
+async fn spawn_admin_listener(config: &config::Config, state: server::AppState) {
+    let bind_addr = config.admin_bind_address();
+    let addr: SocketAddr = bind_addr.parse().unwrap_or_else(|_| {
+        error!("Invalid admin bind address in config: {}", bind_addr);
+        std::process::exit(1);
+    });
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .unwrap_or_else(|e| {
+            error!("Failed to bind admin listener to {}: {}", addr, e);
+            std::process::exit(1);
+        });
+    info!("Metrics available at: http://{}/metrics", addr);
+    info!("Log level control available at: http://{}/logging", addr);
+    info!(
+        "Exports available at: http://{}/export/heartbeats and /export/devices",
+        addr
+    );
 
-    let stdout = ConsoleAppender::builder()
-        .encoder(Box::new(PatternEncoder::new(
-            "{d(%Y-%m-%d %H:%M:%S)} [{h({l})}] {t} - {m}{n}",
-        )))
-        .build();
-
-    let config = Config::builder()
-        .appender(Appender::builder().build("stdout", Box::new(stdout)))
-        .build(
-            Root::builder()
-                .appender("stdout")
-                .build(log::LevelFilter::Info),
-        )?;
-
-    log4rs::init_config(config)?;
-    Ok(())
+    let app = admin::create_admin_router(state);
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            error!("Admin server error: {}", e);
+        }
+    });
 }
 
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let export_mode = args.first().map(String::as_str) == Some("export");
+
     // Load configuration
     let config = match config::Config::load_or_default("config.toml") {
         Ok(cfg) => cfg,
@@ -44,53 +175,106 @@ async fn main() {
         }
     };
 
-    // Initialize logging
-    if let Err(e) = init_logging() {
-        eprintln!("Failed to initialize logging: {}", e);
+    if let Err(e) = config.validate() {
+        eprintln!("Invalid configuration: {:#}", e);
         std::process::exit(1);
     }
 
-    info!("Starting {} v{}...", config.app.name, config.app.version);
-    info!("Configuration loaded from config.toml");
-    info!("Database URL: {}", config.database_url());
+    // Initialize logging
+    let log_control = match logging::init(&config, export_mode) {
+        Ok(control) => control,
+        Err(e) => {
+            eprintln!("Failed to initialize logging: {:#}", e);
+            std::process::exit(1);
+        }
+    };
 
-    // Create database connection pool
-    let db_opts = OptsBuilder::new()
-        .ip_or_hostname(Some(&config.database.host))
-        .tcp_port(config.database.port)
-        .user(Some(&config.database.username))
-        .pass(Some(&config.database.password))
-        .db_name(Some(&config.database.database));
+    if export_mode {
+        run_export(&config, &args[1..]);
+        return;
+    }
 
-    let db_pool = match Pool::new(db_opts) {
-        Ok(pool) => {
-            info!("Database connection pool created successfully");
-            info!("Pool size: {}", config.database.pool_size);
-            pool
+    info!("Starting {} v{}...", config.app.name, config.app.version);
+    info!("Configuration loaded from config.toml");
+    if let Some(path) = log_control.config_file() {
+        info!("Logging configured by {}", path);
+    } else {
+        info!("Log level: {}", config.app.log_level);
+        for (target, level) in &config.logging.levels {
+            info!("Log level for {}: {}", target, level);
         }
-        Err(e) => {
-            error!("Failed to create database connection pool: {}", e);
-            std::process::exit(1);
+        if config.logging.file.enabled {
+            info!("Logging to file {}", config.logging.file.path);
         }
+    }
+
+    let store = match config.database.backend {
+        config::StoreBackend::Mysql => create_mysql_store(&config, config.database.migrations),
+        config::StoreBackend::Memory => create_memory_store(&config),
     };
 
+    let metrics = Arc::new(if config.statsd.enabled {
+        metrics::MetricsRegistry::with_statsd(statsd::StatsdSink::spawn(
+            config.statsd.clone(),
+            &config.app.name,
+            &config.app.version,
+        ))
+    } else {
+        metrics::MetricsRegistry::new()
+    });
+
+    // All store calls run on dedicated worker threads, one per pooled connection
+    let db = executor::DbExecutor::new(
+        store,
+        config.database.pool_size as usize,
+        config.database.queue_capacity,
+        breaker::CircuitBreaker::new(config.database.circuit_breaker.clone()),
+        metrics.clone(),
+    );
+    let writer = writer::HeartbeatWriter::spawn(db.clone(), config.database.writer_queue_capacity);
+
+    let retention = retention::RetentionJob::spawn(db.clone(), config.retention.clone());
+    let webhooks = webhooks::WebhookDispatcher::spawn(config.webhooks.clone());
+    let cache = app::CacheWarmup::spawn(db.clone());
+    let health = health::HealthMonitor::spawn(
+        health::standard_checks(&config.health, db.clone(), writer.clone(), cache.clone()),
+        &config.health,
+    );
+    let state = server::AppState::new(server::AppCore {
+        service_name: config.app.name.clone(),
+        version: config.app.version.clone(),
+        health_count: metrics.counter("health_requests_total", "Requests to /health", &[]),
+        hbd_count: metrics.counter("hbd_requests_total", "Requests to /hbd", &[]),
+        slow_request_threshold: Duration::from_millis(config.app.slow_request_ms),
+        device_cache_ttl: Duration::from_secs(config.database.device_cache_ttl_seconds),
+        exports: Arc::new(tokio::sync::Semaphore::new(
+            config.database.max_concurrent_exports,
+        )),
+        metrics,
+        db,
+        writer,
+        retention,
+        webhooks,
+        cache,
+        health,
+        logging: log_control,
+    });
+
     // Test database connectivity
-    match db_pool.get_conn() {
-        Ok(mut conn) => match conn.query_drop("SELECT 1") {
-            Ok(_) => info!("Database connectivity test successful"),
-            Err(e) => {
-                error!("Database connectivity test failed: {}", e);
-                std::process::exit(1);
-            }
-        },
-        Err(e) => {
-            error!("Failed to get initial database connection: {}", e);
-            std::process::exit(1);
-        }
+    if !state.is_db_healthy().await {
+        error!("Database connectivity test failed");
+        std::process::exit(1);
     }
+    info!("Database connectivity test successful");
 
     // Build our application with routes
-    let app = server::create_router(db_pool);
+    if config.admin.enabled {
+        spawn_admin_listener(&config, state.clone()).await;
+    } else {
+        info!("Admin listener disabled");
+    }
+
+    let app = server::create_router(state);
 
     // Server address from config
     let bind_addr = config.bind_address();
@@ -101,7 +285,15 @@ async fn main() {
 
     info!("Server will listen on: {}", addr);
     info!("Health endpoint available at: http://{}/health", addr);
+    info!(
+        "Probes available at: http://{}/livez and http://{}/readyz",
+        addr, addr
+    );
     info!("HBD endpoint available at: http://{}/hbd", addr);
+    info!(
+        "Heartbeat history available at: http://{}/devices/<mac>/heartbeats",
+        addr
+    );
     info!("Server protocol: HTTP/1.1");
     info!("Server framework: Axum v0.7");
 
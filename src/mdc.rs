@@ -0,0 +1,70 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{self, Poll};
+
+/// Current value of `key` in this thread's MDC
+pub fn mdc_get(key: &str) -> Option<String> {
+    log_mdc::get(key, |value| value.map(str::to_string))
+}
+
+/// Run `f` with `key` set in the MDC, for work handed to another thread
+pub fn mdc_scope<R>(key: &'static str, value: Option<String>, f: impl FnOnce() -> R) -> R {
+    let _guard = MdcGuard::set(key, value);
+    f()
+}
+
+/// Future that puts `key` in the MDC each time it is polled
+///
+/// The MDC is thread-local and a task can move between runtime threads, so
+/// the value is set around every poll and the previous value restored after.
+pub struct WithMdc<F> {
+    key: &'static str,
+    value: Option<String>,
+    inner: Pin<Box<F>>,
+}
+
+impl<F: Future> WithMdc<F> {
+    pub fn new(key: &'static str, value: Option<String>, inner: F) -> Self {
+        Self {
+            key,
+            value,
+            inner: Box::pin(inner),
+        }
+    }
+}
+
+impl<F: Future> Future for WithMdc<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<F::Output> {
+        let this = self.get_mut();
+        let _guard = MdcGuard::set(this.key, this.value.clone());
+        this.inner.as_mut().poll(cx)
+    }
+}
+
+/// Restores whatever value the thread had before when dropped
+struct MdcGuard {
+    key: &'static str,
+    previous: Option<String>,
+}
+
+impl MdcGuard {
+    fn set(key: &'static str, value: Option<String>) -> Self {
+        let previous = mdc_get(key);
+        match value {
+            Some(value) => log_mdc::insert(key, value),
+            None => log_mdc::remove(key),
+        };
+        Self { key, previous }
+    }
+}
+
+impl Drop for MdcGuard {
+    fn drop(&mut self) {
+        match self.previous.take() {
+            Some(value) => log_mdc::insert(self.key, value),
+            None => log_mdc::remove(self.key),
+        };
+    }
+}
@@ -0,0 +1,466 @@
+use anyhow::{Context, Result};
+use log::{info, warn};
+use mysql::prelude::Queryable;
+use mysql::{Pool, PooledConn};
+
+use crate::config::MigrationMode;
+
+/// A versioned schema change embedded in the binary
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub sql: &'static str,
+    /// Columns the migration creates, by table
+    pub tables: &'static [(&'static str, &'static [&'static str])],
+    /// Stored procedures the migration creates
+    pub procedures: &'static [&'static str],
+}
+
+/// Every migration the binary knows about, in version order
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        sql: include_str!("../migrations/V001__initial_schema.sql"),
+        tables: &[
+            ("accounts", &["id", "name", "active"]),
+            (
+                "devices",
+                &["id", "mac_address", "account_id", "active", "squelch"],
+            ),
+            (
+                "heartbeats",
+                &[
+                    "id",
+                    "device_id",
+                    "mac_address",
+                    "reported_ip",
+                    "public_ip",
+                    "lp",
+                    "device_ts",
+                    "received_at",
+                ],
+            ),
+        ],
+        procedures: &["is_device_active"],
+    },
+    Migration {
+        version: 2,
+        description: "hourly heartbeat rollups",
+        sql: include_str!("../migrations/V002__heartbeat_hourly.sql"),
+        tables: &[(
+            "heartbeat_hourly",
+            &[
+                "mac_address",
+                "hour_start",
+                "device_id",
+                "heartbeat_count",
+                "first_seen",
+                "last_seen",
+                "distinct_reported_ips",
+                "distinct_public_ips",
+            ],
+        )],
+        procedures: &[],
+    },
+    Migration {
+        version: 3,
+        description: "long poll in hourly rollups",
+        sql: include_str!("../migrations/V003__heartbeat_hourly_lp.sql"),
+        tables: &[("heartbeat_hourly", &["max_lp"])],
+        procedures: &[],
+    },
+];
+
+/// Longest a starting instance waits for another one to finish migrating
+const MIGRATION_LOCK_TIMEOUT_SECONDS: u32 = 60;
+
+/// Schema version this binary expects
+pub fn expected_version() -> u32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+/// How much of a migration's schema objects the database already has
+enum Presence {
+    Complete,
+    Absent,
+    /// Some objects exist; lists the ones that do not
+    Partial(Vec<String>),
+}
+
+/// Bring the schema up to date, or check that it already is, depending on `mode`
+pub fn run(pool: &Pool, mode: MigrationMode) -> Result<()> {
+    if mode == MigrationMode::Off {
+        info!("Schema migrations disabled");
+        return Ok(());
+    }
+
+    let mut conn = pool
+        .get_conn()
+        .context("Failed to get connection for schema migrations")?;
+
+    match mode {
+        MigrationMode::Verify => verify(&mut conn),
+        _ => {
+            lock(&mut conn)?;
+            let result = apply(&mut conn);
+            // The lock belongs to the connection, which goes back to the pool
+            if let Err(e) =
+                conn.query_drop("SELECT RELEASE_LOCK(CONCAT(DATABASE(), '.schema_migrations'))")
+            {
+                warn!("Failed to release schema migration lock: {}", e);
+            }
+            result
+        }
+    }
+}
+
+/// Check the schema without changing anything
+fn verify(conn: &mut PooledConn) -> Result<()> {
+    let expected = expected_version();
+    let Some(current) = current_version(conn)? else {
+        // Deployments that predate `schema_version` are accepted as long as
+        // they already have everything the binary uses
+        let missing = missing_objects(conn)?;
+        if missing.is_empty() {
+            warn!(
+                "Database schema is not versioned but matches version {}; \
+                 run once with database.migrations = \"apply\" to record it",
+                expected
+            );
+            return Ok(());
+        }
+        anyhow::bail!(
+            "Database schema is not versioned and is missing {}; \
+             run with database.migrations = \"apply\" to upgrade",
+            missing.join(", ")
+        );
+    };
+    info!(
+        "Database schema version: {}, expected: {}",
+        current, expected
+    );
+
+    if current > expected {
+        warn!(
+            "Database schema version {} is newer than this binary expects ({})",
+            current, expected
+        );
+        return Ok(());
+    }
+    if current < expected {
+        anyhow::bail!(
+            "Database schema version {} is older than required version {}; \
+             run with database.migrations = \"apply\" to upgrade",
+            current,
+            expected
+        );
+    }
+    ensure_complete(conn)
+}
+
+/// Apply pending migrations, recording existing unversioned schemas as a baseline
+fn apply(conn: &mut PooledConn) -> Result<()> {
+    let expected = expected_version();
+    let versioned = current_version(conn)?;
+    info!(
+        "Database schema version: {}, expected: {}",
+        versioned.map_or_else(|| "unversioned".to_string(), |v| v.to_string()),
+        expected
+    );
+
+    conn.query_drop(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INT UNSIGNED NOT NULL,
+            description VARCHAR(255) NOT NULL,
+            applied_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY (version)
+        ) ENGINE = InnoDB",
+    )
+    .context("Failed to create schema_version table")?;
+
+    let current = versioned.unwrap_or(0);
+    if current > expected {
+        warn!(
+            "Database schema version {} is newer than this binary expects ({})",
+            current, expected
+        );
+        return Ok(());
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        if versioned.is_none() {
+            match presence(conn, migration)? {
+                Presence::Complete => {
+                    info!(
+                        "Recording existing schema as migration V{:03}: {}",
+                        migration.version, migration.description
+                    );
+                    record(conn, migration, "baseline")?;
+                    continue;
+                }
+                Presence::Partial(missing) => anyhow::bail!(
+                    "Database schema partly matches migration V{:03} ({}) but is missing {}; \
+                     upgrade these objects by hand, then start again",
+                    migration.version,
+                    migration.description,
+                    missing.join(", ")
+                ),
+                Presence::Absent => {}
+            }
+        }
+
+        info!(
+            "Applying migration V{:03}: {}",
+            migration.version, migration.description
+        );
+        for statement in split_statements(migration.sql) {
+            conn.query_drop(&statement).with_context(|| {
+                format!(
+                    "Migration V{:03} failed on statement: {}",
+                    migration.version, statement
+                )
+            })?;
+        }
+        record(conn, migration, "applied")?;
+    }
+
+    ensure_complete(conn)?;
+    info!("Database schema is at version {}", expected);
+    Ok(())
+}
+
+/// Take the migration lock so instances starting together migrate one at a time
+fn lock(conn: &mut PooledConn) -> Result<()> {
+    let locked: Option<Option<i64>> = conn
+        .exec_first(
+            "SELECT GET_LOCK(CONCAT(DATABASE(), '.schema_migrations'), ?)",
+            (MIGRATION_LOCK_TIMEOUT_SECONDS,),
+        )
+        .context("Failed to take schema migration lock")?;
+    match locked.flatten() {
+        Some(1) => Ok(()),
+        _ => anyhow::bail!(
+            "Timed out after {}s waiting for another instance to finish migrating",
+            MIGRATION_LOCK_TIMEOUT_SECONDS
+        ),
+    }
+}
+
+/// Highest applied version, or `None` when the schema is not versioned
+///
+/// An empty `schema_version` counts as unversioned: earlier releases created
+/// the table on every start, even when they did not migrate.
+fn current_version(conn: &mut PooledConn) -> Result<Option<u32>> {
+    let versioned: Option<u8> = conn.query_first(
+        "SELECT 1 FROM information_schema.TABLES \
+         WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = 'schema_version'",
+    )?;
+    if versioned.is_none() {
+        return Ok(None);
+    }
+    let current: Option<Option<u32>> =
+        conn.query_first("SELECT MAX(version) FROM schema_version")?;
+    Ok(current.flatten())
+}
+
+fn record(conn: &mut PooledConn, migration: &Migration, how: &str) -> Result<()> {
+    conn.exec_drop(
+        "INSERT INTO schema_version (version, description) VALUES (?, ?)",
+        (
+            migration.version,
+            format!("{} ({})", migration.description, how),
+        ),
+    )?;
+    Ok(())
+}
+
+/// Fail when any object created by a known migration is missing
+///
+/// `CREATE TABLE IF NOT EXISTS` keeps an older table as it is, so a version
+/// number alone does not prove the columns the binary queries exist.
+fn ensure_complete(conn: &mut PooledConn) -> Result<()> {
+    let missing = missing_objects(conn)?;
+    if !missing.is_empty() {
+        anyhow::bail!(
+            "Database schema is missing {}; upgrade these objects by hand",
+            missing.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn missing_objects(conn: &mut PooledConn) -> Result<Vec<String>> {
+    let mut missing = Vec::new();
+    for migration in MIGRATIONS {
+        match presence(conn, migration)? {
+            Presence::Complete => {}
+            Presence::Absent => missing.extend(objects(migration)),
+            Presence::Partial(objects) => missing.extend(objects),
+        }
+    }
+    Ok(missing)
+}
+
+fn objects(migration: &Migration) -> Vec<String> {
+    let tables = migration
+        .tables
+        .iter()
+        .map(|(table, _)| format!("table {}", table));
+    let procedures = migration
+        .procedures
+        .iter()
+        .map(|procedure| format!("procedure {}", procedure));
+    tables.chain(procedures).collect()
+}
+
+fn presence(conn: &mut PooledConn, migration: &Migration) -> Result<Presence> {
+    let mut missing = Vec::new();
+    let mut found = 0;
+
+    for (table, columns) in migration.tables {
+        let present: Vec<String> = conn.exec(
+            "SELECT COLUMN_NAME FROM information_schema.COLUMNS \
+             WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ?",
+            (table,),
+        )?;
+        if present.is_empty() {
+            missing.push(format!("table {}", table));
+            continue;
+        }
+        for column in columns.iter() {
+            if present.iter().any(|p| p.eq_ignore_ascii_case(column)) {
+                found += 1;
+            } else {
+                missing.push(format!("column {}.{}", table, column));
+            }
+        }
+    }
+    for procedure in migration.procedures {
+        let exists: Option<u8> = conn.exec_first(
+            "SELECT 1 FROM information_schema.ROUTINES \
+             WHERE ROUTINE_SCHEMA = DATABASE() AND ROUTINE_TYPE = 'PROCEDURE' \
+               AND ROUTINE_NAME = ?",
+            (procedure,),
+        )?;
+        match exists {
+            Some(_) => found += 1,
+            None => missing.push(format!("procedure {}", procedure)),
+        }
+    }
+
+    Ok(if missing.is_empty() {
+        Presence::Complete
+    } else if found == 0 {
+        Presence::Absent
+    } else {
+        Presence::Partial(missing)
+    })
+}
+
+/// Split a migration script into statements
+///
+/// Understands the `DELIMITER` directive of the mysql command line client so
+/// stored procedure bodies can contain `;`, and the same files can be applied
+/// by hand with `mysql < file.sql`.
+fn split_statements(sql: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut delimiter = ";".to_string();
+    let mut current = String::new();
+
+    for line in sql.lines() {
+        let trimmed = line.trim();
+
+        if let Some(new_delimiter) = trimmed.strip_prefix("DELIMITER ") {
+            delimiter = new_delimiter.trim().to_string();
+            continue;
+        }
+        if current.is_empty() && (trimmed.is_empty() || trimmed.starts_with("--")) {
+            continue;
+        }
+
+        match trimmed.strip_suffix(delimiter.as_str()) {
+            Some(rest) => {
+                current.push_str(rest);
+                statements.push(std::mem::take(&mut current));
+            }
+            None => {
+                current.push_str(line);
+                current.push('\n');
+            }
+        }
+    }
+
+    if !current.trim().is_empty() {
+        statements.push(current);
+    }
+    statements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_on_semicolons_and_skips_comments() {
+        let sql = "-- leading comment\n\
+                   CREATE TABLE a (id INT);\n\
+                   \n\
+                   -- another\n\
+                   CREATE TABLE b (\n    id INT\n);\n";
+
+        let statements = split_statements(sql);
+
+        assert_eq!(
+            statements,
+            vec!["CREATE TABLE a (id INT)", "CREATE TABLE b (\n    id INT\n)"]
+        );
+    }
+
+    #[test]
+    fn keeps_semicolons_inside_delimiter_blocks() {
+        let sql = "DELIMITER $$\n\
+                   CREATE PROCEDURE p()\n\
+                   BEGIN\n    SELECT 1;\n    SELECT 2;\n\
+                   END$$\n\
+                   DELIMITER ;\n\
+                   CREATE TABLE c (id INT);\n";
+
+        let statements = split_statements(sql);
+
+        assert_eq!(statements.len(), 2);
+        assert_eq!(
+            statements[0],
+            "CREATE PROCEDURE p()\nBEGIN\n    SELECT 1;\n    SELECT 2;\nEND"
+        );
+        assert_eq!(statements[1], "CREATE TABLE c (id INT)");
+    }
+
+    #[test]
+    fn keeps_a_trailing_statement_without_delimiter() {
+        let statements = split_statements("SELECT 1;\nSELECT 2\n");
+
+        assert_eq!(statements, vec!["SELECT 1", "SELECT 2\n"]);
+    }
+
+    #[test]
+    fn splits_the_embedded_migrations() {
+        let initial = split_statements(MIGRATIONS[0].sql);
+        assert_eq!(initial.len(), 4);
+        assert!(initial[3].starts_with("CREATE PROCEDURE is_device_active"));
+        assert!(initial[3].trim_end().ends_with("END"));
+        assert!(initial.iter().all(|s| !s.contains("DELIMITER")));
+
+        for migration in MIGRATIONS {
+            assert!(!split_statements(migration.sql).is_empty());
+        }
+    }
+
+    #[test]
+    fn migrations_are_in_version_order() {
+        for pair in MIGRATIONS.windows(2) {
+            assert!(pair[0].version < pair[1].version);
+        }
+        assert_eq!(expected_version(), MIGRATIONS.last().unwrap().version);
+    }
+}
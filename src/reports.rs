@@ -0,0 +1,475 @@
+use chrono::{DateTime, Duration, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::app::{ApiError, parse_time_param, store_error};
+use crate::server::AppState;
+use crate::store::{ActivitySpan, DeviceRecord};
+
+/// Interval assumed for devices that never reported a long poll
+pub const DEFAULT_EXPECTED_INTERVAL_SECS: i64 = 60;
+/// A gap longer than this many expected intervals counts as an outage
+pub const OUTAGE_GRACE_FACTOR: i64 = 2;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AvailabilityWindow {
+    #[default]
+    Day,
+    Week,
+    Month,
+}
+
+impl AvailabilityWindow {
+    pub fn duration(&self) -> Duration {
+        match self {
+            Self::Day => Duration::days(1),
+            Self::Week => Duration::weeks(1),
+            Self::Month => Duration::days(30),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AvailabilityParams {
+    pub window: Option<AvailabilityWindow>,
+    /// End of the window, defaults to now
+    pub end: Option<String>,
+}
+
+/// Uptime figures for one device over a window
+#[derive(Clone, Debug, Serialize)]
+pub struct AvailabilityStats {
+    pub expected_interval_seconds: i64,
+    pub heartbeat_count: usize,
+    pub uptime_percent: f64,
+    pub downtime_seconds: i64,
+    pub outage_count: u32,
+    pub longest_gap_seconds: i64,
+}
+
+#[derive(Serialize)]
+pub struct DeviceAvailability {
+    pub mac: String,
+    pub window: AvailabilityWindow,
+    pub from: String,
+    pub to: String,
+    #[serde(flatten)]
+    pub stats: AvailabilityStats,
+}
+
+#[derive(Serialize)]
+pub struct AccountAvailability {
+    pub account_id: Option<i32>,
+    pub device_count: usize,
+    pub average_uptime_percent: f64,
+    pub min_uptime_percent: f64,
+    pub outage_count: u32,
+    pub longest_gap_seconds: i64,
+}
+
+#[derive(Serialize)]
+pub struct FleetAvailability {
+    pub window: AvailabilityWindow,
+    pub from: String,
+    pub to: String,
+    pub device_count: usize,
+    pub average_uptime_percent: f64,
+    pub accounts: Vec<AccountAvailability>,
+}
+
+/// Business logic for SLA / availability reports
+pub struct ReportService;
+
+impl ReportService {
+    /// Availability of a single device over the requested window
+    pub async fn device_availability(
+        state: &AppState,
+        mac: String,
+        params: AvailabilityParams,
+    ) -> Result<DeviceAvailability, ApiError> {
+        let window = params.window.unwrap_or_default();
+        let (from, to) = Self::window_bounds(window, params.end.as_deref())?;
+        info!(
+            mac = mac.as_str(),
+            window:serde = window,
+            from:% = from,
+            to:% = to;
+            "Availability report requested"
+        );
+
+        let lookup_mac = mac.clone();
+        let spans = state
+            .db
+            .run("activity_spans", move |store| {
+                let mut spans = Vec::new();
+                store.activity_spans(Some(&lookup_mac), from, to, &mut |span| {
+                    spans.push(span);
+                    Ok(())
+                })?;
+                Ok(spans)
+            })
+            .await
+            .map_err(store_error)?;
+
+        Ok(DeviceAvailability {
+            mac,
+            window,
+            from: from.to_rfc3339(),
+            to: to.to_rfc3339(),
+            stats: compute_availability(&spans, from, to),
+        })
+    }
+
+    /// Availability of every active device, rolled up per account
+    pub async fn fleet_availability(
+        state: &AppState,
+        params: AvailabilityParams,
+    ) -> Result<FleetAvailability, ApiError> {
+        let window = params.window.unwrap_or_default();
+        let (from, to) = Self::window_bounds(window, params.end.as_deref())?;
+        info!(
+            window:serde = window,
+            from:% = from,
+            to:% = to;
+            "Fleet availability report requested"
+        );
+
+        // One job and two queries for the whole fleet, so a report can neither
+        // flood the db queue nor run a query per device
+        let device_stats = state
+            .db
+            .run("fleet_availability", move |store| {
+                let mut tally = FleetTally::new(store.list_devices()?, from, to);
+                store.activity_spans(None, from, to, &mut |span| {
+                    tally.push(span);
+                    Ok(())
+                })?;
+                Ok(tally.finish())
+            })
+            .await
+            .map_err(store_error)?;
+
+        let mut by_account: BTreeMap<Option<i32>, Vec<AvailabilityStats>> = BTreeMap::new();
+        for (account_id, stats) in &device_stats {
+            by_account
+                .entry(*account_id)
+                .or_default()
+                .push(stats.clone());
+        }
+
+        let accounts = by_account
+            .into_iter()
+            .map(|(account_id, stats)| AccountAvailability {
+                account_id,
+                device_count: stats.len(),
+                average_uptime_percent: average_uptime(stats.iter()),
+                min_uptime_percent: stats.iter().map(|s| s.uptime_percent).fold(100.0, f64::min),
+                outage_count: stats.iter().map(|s| s.outage_count).sum(),
+                longest_gap_seconds: stats
+                    .iter()
+                    .map(|s| s.longest_gap_seconds)
+                    .max()
+                    .unwrap_or(0),
+            })
+            .collect();
+
+        Ok(FleetAvailability {
+            window,
+            from: from.to_rfc3339(),
+            to: to.to_rfc3339(),
+            device_count: device_stats.len(),
+            average_uptime_percent: average_uptime(device_stats.iter().map(|(_, s)| s)),
+            accounts,
+        })
+    }
+
+    fn window_bounds(
+        window: AvailabilityWindow,
+        end: Option<&str>,
+    ) -> Result<(DateTime<Utc>, DateTime<Utc>), ApiError> {
+        let now = Utc::now();
+        let to = match end {
+            Some(value) => parse_time_param("end", value)?.min(now),
+            None => now,
+        };
+        Ok((to - window.duration(), to))
+    }
+}
+
+/// Availability of every active device from one stream of spans ordered by MAC
+///
+/// Only one device's spans are held at a time.
+struct FleetTally {
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    /// Active devices not reported yet, with their account
+    pending: HashMap<String, Option<i32>>,
+    current: Option<String>,
+    spans: Vec<ActivitySpan>,
+    results: Vec<(Option<i32>, AvailabilityStats)>,
+}
+
+impl FleetTally {
+    fn new(devices: Vec<DeviceRecord>, from: DateTime<Utc>, to: DateTime<Utc>) -> Self {
+        Self {
+            from,
+            to,
+            pending: devices
+                .into_iter()
+                .filter(|d| d.active)
+                .map(|d| (d.mac, d.account_id))
+                .collect(),
+            current: None,
+            spans: Vec::new(),
+            results: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, span: ActivitySpan) {
+        if self.current.as_deref() != Some(span.mac.as_str()) {
+            self.flush();
+            self.current = Some(span.mac.clone());
+        }
+        self.spans.push(span);
+    }
+
+    fn flush(&mut self) {
+        let spans = std::mem::take(&mut self.spans);
+        // Inactive and unregistered devices are skipped
+        if let Some(account_id) = self
+            .current
+            .take()
+            .and_then(|mac| self.pending.remove(&mac))
+        {
+            let stats = compute_availability(&spans, self.from, self.to);
+            self.results.push((account_id, stats));
+        }
+    }
+
+    /// Devices without any activity in the window were down throughout
+    fn finish(mut self) -> Vec<(Option<i32>, AvailabilityStats)> {
+        self.flush();
+        for account_id in std::mem::take(&mut self.pending).into_values() {
+            let stats = compute_availability(&[], self.from, self.to);
+            self.results.push((account_id, stats));
+        }
+        self.results
+    }
+}
+
+fn average_uptime<'a>(stats: impl Iterator<Item = &'a AvailabilityStats>) -> f64 {
+    let (total, count) = stats.fold((0.0, 0usize), |(total, count), s| {
+        (total + s.uptime_percent, count + 1)
+    });
+    if count == 0 {
+        100.0
+    } else {
+        round2(total / count as f64)
+    }
+}
+
+fn round2(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
+
+/// Work out uptime from the gaps between heartbeats
+///
+/// `spans` must be in time order. The expected interval is the device's last
+/// reported long poll. Any gap between spans (including from the window start
+/// to the first one, and from the last one to the window end) longer than
+/// `OUTAGE_GRACE_FACTOR` intervals is an outage; the time beyond one interval
+/// counts as downtime. A rolled-up hour has no gaps inside it.
+pub fn compute_availability(
+    spans: &[ActivitySpan],
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> AvailabilityStats {
+    let expected_interval = spans
+        .iter()
+        .rev()
+        .filter_map(|s| s.lp)
+        .find(|lp| *lp > 0)
+        .map(|lp| lp as i64)
+        .unwrap_or(DEFAULT_EXPECTED_INTERVAL_SECS);
+    let threshold = expected_interval * OUTAGE_GRACE_FACTOR;
+
+    // A rolled-up hour may start before the window
+    let mut gaps = Vec::with_capacity(spans.len() + 1);
+    let mut last_seen = from;
+    for span in spans {
+        let first_seen = span.first_seen.clamp(from, to);
+        gaps.push((first_seen - last_seen).num_seconds().max(0));
+        last_seen = last_seen.max(span.last_seen.clamp(from, to));
+    }
+    gaps.push((to - last_seen).num_seconds());
+
+    let mut downtime = 0;
+    let mut outage_count = 0;
+    for gap in gaps.iter().filter(|gap| **gap > threshold) {
+        outage_count += 1;
+        downtime += gap - expected_interval;
+    }
+
+    let window_seconds = (to - from).num_seconds().max(1);
+    let downtime = downtime.min(window_seconds);
+
+    AvailabilityStats {
+        expected_interval_seconds: expected_interval,
+        heartbeat_count: spans.iter().map(|s| s.heartbeat_count as usize).sum(),
+        uptime_percent: round2(100.0 * (window_seconds - downtime) as f64 / window_seconds as f64),
+        downtime_seconds: downtime,
+        outage_count,
+        longest_gap_seconds: gaps.into_iter().max().unwrap_or(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{DeviceStore, HeartbeatRecord, InMemoryDeviceStore};
+    use chrono::TimeZone;
+
+    fn start() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap()
+    }
+
+    fn heartbeat(mac: &str, at: DateTime<Utc>) -> ActivitySpan {
+        ActivitySpan {
+            mac: mac.to_string(),
+            first_seen: at,
+            last_seen: at,
+            heartbeat_count: 1,
+            lp: Some(60),
+        }
+    }
+
+    /// Heartbeats every `lp` seconds in `[from, to)`
+    fn steady(mac: &str, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<ActivitySpan> {
+        let mut spans = Vec::new();
+        let mut at = from;
+        while at < to {
+            spans.push(heartbeat(mac, at));
+            at += Duration::seconds(60);
+        }
+        spans
+    }
+
+    #[test]
+    fn steady_heartbeats_are_fully_available() {
+        let (from, to) = (start(), start() + Duration::hours(1));
+
+        let stats = compute_availability(&steady("a", from, to), from, to);
+
+        assert_eq!(stats.heartbeat_count, 60);
+        assert_eq!(stats.outage_count, 0);
+        assert_eq!(stats.uptime_percent, 100.0);
+    }
+
+    #[test]
+    fn a_long_gap_is_an_outage() {
+        let (from, to) = (start(), start() + Duration::hours(1));
+        let mut spans = steady("a", from, from + Duration::minutes(20));
+        spans.extend(steady("a", from + Duration::minutes(30), to));
+
+        let stats = compute_availability(&spans, from, to);
+
+        assert_eq!(stats.outage_count, 1);
+        assert_eq!(stats.longest_gap_seconds, 660);
+        assert_eq!(stats.downtime_seconds, 600);
+    }
+
+    #[test]
+    fn a_rolled_up_hour_has_no_gaps_inside() {
+        let (from, to) = (start(), start() + Duration::hours(1));
+        let hour = ActivitySpan {
+            mac: "a".to_string(),
+            first_seen: from,
+            last_seen: to - Duration::seconds(30),
+            heartbeat_count: 60,
+            lp: Some(60),
+        };
+
+        let stats = compute_availability(&[hour], from, to);
+
+        assert_eq!(stats.heartbeat_count, 60);
+        assert_eq!(stats.outage_count, 0);
+    }
+
+    #[test]
+    fn fleet_tally_reports_each_active_device_once() {
+        let (from, to) = (start(), start() + Duration::hours(1));
+        let device = |mac: &str, account_id, active| DeviceRecord {
+            mac: mac.to_string(),
+            account_id,
+            active,
+            squelched: false,
+        };
+        let mut tally = FleetTally::new(
+            vec![
+                device("a", Some(1), true),
+                device("b", Some(2), true),
+                device("c", Some(2), false),
+            ],
+            from,
+            to,
+        );
+        for span in steady("a", from, to)
+            .into_iter()
+            .chain(steady("c", from, to))
+        {
+            tally.push(span);
+        }
+
+        let mut results = tally.finish();
+        results.sort_by_key(|(account_id, _)| *account_id);
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, Some(1));
+        assert_eq!(results[0].1.uptime_percent, 100.0);
+        assert_eq!(results[1].0, Some(2));
+        assert_eq!(results[1].1.heartbeat_count, 0);
+    }
+
+    #[test]
+    fn rolled_up_hours_are_not_counted_twice() {
+        let store = InMemoryDeviceStore::new();
+        for span in steady("a", start(), start() + Duration::hours(3)) {
+            store
+                .persist_heartbeat(&HeartbeatRecord {
+                    device_id: 1,
+                    mac: span.mac,
+                    reported_ip: "10.0.0.1".to_string(),
+                    public_ip: "192.0.2.1".to_string(),
+                    lp: span.lp,
+                    device_ts: None,
+                    received_at: span.first_seen,
+                })
+                .unwrap();
+        }
+        // The first hour is rolled up but its raw rows are not deleted yet
+        store
+            .rollup_heartbeats(start(), start() + Duration::hours(1))
+            .unwrap();
+
+        let mut spans = Vec::new();
+        store
+            .activity_spans(
+                Some("a"),
+                start(),
+                start() + Duration::hours(3),
+                &mut |span| {
+                    spans.push(span);
+                    Ok(())
+                },
+            )
+            .unwrap();
+
+        assert_eq!(spans.len(), 1 + 120);
+        assert_eq!(spans[0].heartbeat_count, 60);
+        let stats = compute_availability(&spans, start(), start() + Duration::hours(3));
+        assert_eq!(stats.heartbeat_count, 180);
+        assert_eq!(stats.outage_count, 0);
+    }
+}
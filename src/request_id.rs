@@ -0,0 +1,50 @@
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use uuid::Uuid;
+
+use crate::mdc::{self, WithMdc};
+
+pub static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Key under which the id is put in the log4rs MDC, shown by `{X(request_id)}`
+pub const MDC_KEY: &str = "request_id";
+
+/// Longest incoming `X-Request-Id` that is reused rather than replaced
+const MAX_LENGTH: usize = 128;
+
+/// Reuse the caller's `X-Request-Id` or generate one, echo it in the
+/// response, and tag every log line written while handling the request
+pub async fn propagate_request_id(request: Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| is_valid(value))
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let mut response = WithMdc::new(MDC_KEY, Some(request_id.clone()), next.run(request)).await;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response
+            .headers_mut()
+            .insert(REQUEST_ID_HEADER.clone(), value);
+    }
+    response
+}
+
+fn is_valid(value: &str) -> bool {
+    !value.is_empty() && value.len() <= MAX_LENGTH && value.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// The id of the request handled by the current thread, if any
+pub fn current() -> Option<String> {
+    mdc::mdc_get(MDC_KEY)
+}
+
+/// Run `f` with `request_id` in the MDC, for work handed to another thread
+pub fn scope<R>(request_id: Option<String>, f: impl FnOnce() -> R) -> R {
+    mdc::mdc_scope(MDC_KEY, request_id, f)
+}
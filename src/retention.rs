@@ -0,0 +1,161 @@
+use anyhow::Result;
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use log::{error, info};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+
+use crate::config::RetentionConfig;
+use crate::executor::DbExecutor;
+
+/// Progress of the retention job, reported by `/health`
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RetentionStatus {
+    pub enabled: bool,
+    pub running: bool,
+    pub max_age_hours: u64,
+    pub last_run_started: Option<String>,
+    pub last_run_finished: Option<String>,
+    pub last_cutoff: Option<String>,
+    /// Hourly summary rows written by the current or last run
+    pub hours_rolled_up: u64,
+    /// Raw rows deleted by the current or last run
+    pub rows_deleted: u64,
+    pub total_rows_deleted: u64,
+    pub last_error: Option<String>,
+    pub next_run: Option<String>,
+}
+
+/// Rolls old raw heartbeats up into hourly summaries, then deletes them
+#[derive(Clone)]
+pub struct RetentionJob {
+    status: Arc<Mutex<RetentionStatus>>,
+}
+
+impl RetentionJob {
+    /// Start the job on the current tokio runtime, or just report it disabled
+    pub fn spawn(db: DbExecutor, config: RetentionConfig) -> Self {
+        let job = Self {
+            status: Arc::new(Mutex::new(RetentionStatus {
+                enabled: config.enabled,
+                max_age_hours: config.max_age_hours,
+                ..Default::default()
+            })),
+        };
+
+        if !config.enabled {
+            info!("Heartbeat retention job disabled");
+            return job;
+        }
+
+        info!(
+            max_age_hours = config.max_age_hours,
+            interval_seconds = config.interval_seconds,
+            batch_size = config.batch_size;
+            "Heartbeat retention job enabled"
+        );
+
+        let task_job = job.clone();
+        tokio::spawn(async move {
+            let interval = std::time::Duration::from_secs(config.interval_seconds.max(1));
+            loop {
+                if let Err(e) = task_job.run_once(&db, &config).await {
+                    error!(error:% = e; "Heartbeat retention run failed");
+                    task_job.update(|status| status.last_error = Some(e.to_string()));
+                }
+                let next_run = Utc::now() + Duration::from_std(interval).unwrap_or_default();
+                task_job.update(|status| {
+                    status.running = false;
+                    status.next_run = Some(next_run.to_rfc3339());
+                });
+                tokio::time::sleep(interval).await;
+            }
+        });
+
+        job
+    }
+
+    pub fn status(&self) -> RetentionStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut RetentionStatus)) {
+        f(&mut self.status.lock().unwrap());
+    }
+
+    async fn run_once(&self, db: &DbExecutor, config: &RetentionConfig) -> Result<()> {
+        let cutoff = Self::cutoff(Utc::now(), config.max_age_hours);
+        info!(cutoff:% = cutoff; "Heartbeat retention run started");
+        self.update(|status| {
+            status.running = true;
+            status.last_run_started = Some(Utc::now().to_rfc3339());
+            status.last_cutoff = Some(cutoff.to_rfc3339());
+            status.hours_rolled_up = 0;
+            status.rows_deleted = 0;
+            status.last_error = None;
+        });
+
+        let pause = std::time::Duration::from_millis(config.batch_pause_ms);
+
+        // Summaries first, so nothing is deleted before it has been rolled up.
+        // Each statement covers a bounded range, like the deletes below.
+        let oldest = db
+            .run("oldest_heartbeat", |store| store.oldest_heartbeat())
+            .await?;
+        if let Some(oldest) = oldest {
+            let step = Duration::hours(config.rollup_batch_hours.max(1) as i64);
+            let mut from = Self::hour_start(oldest);
+            while from < cutoff {
+                let to = (from + step).min(cutoff);
+                let hours = db
+                    .run("rollup_heartbeats", move |store| {
+                        store.rollup_heartbeats(from, to)
+                    })
+                    .await?;
+                self.update(|status| status.hours_rolled_up += hours);
+                from = to;
+                if from < cutoff {
+                    tokio::time::sleep(pause).await;
+                }
+            }
+        }
+        info!(
+            device_hours = self.status().hours_rolled_up;
+            "Heartbeat retention rollup finished"
+        );
+
+        let batch_size = config.batch_size.max(1);
+        loop {
+            let deleted = db
+                .run("delete_heartbeats", move |store| {
+                    store.delete_heartbeats_before(cutoff, batch_size)
+                })
+                .await?;
+            self.update(|status| {
+                status.rows_deleted += deleted;
+                status.total_rows_deleted += deleted;
+            });
+            if deleted < batch_size as u64 {
+                break;
+            }
+            tokio::time::sleep(pause).await;
+        }
+
+        let status = self.status();
+        info!(
+            device_hours = status.hours_rolled_up,
+            rows_deleted = status.rows_deleted;
+            "Heartbeat retention run finished"
+        );
+        self.update(|status| status.last_run_finished = Some(Utc::now().to_rfc3339()));
+        Ok(())
+    }
+
+    /// Only whole hours are rolled up, so an hour's summary is never split
+    fn cutoff(now: DateTime<Utc>, max_age_hours: u64) -> DateTime<Utc> {
+        Self::hour_start(now - Duration::hours(max_age_hours as i64))
+    }
+
+    fn hour_start(at: DateTime<Utc>) -> DateTime<Utc> {
+        at.duration_trunc(Duration::hours(1)).unwrap_or(at)
+    }
+}
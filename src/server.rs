@@ -1,72 +1,89 @@
-use anyhow::Result;
 use axum::{
-    Router,
-    extract::{ConnectInfo, Query, State},
-    http::{HeaderMap, StatusCode},
-    response::Json,
+    Extension, Router,
+    extract::{ConnectInfo, MatchedPath, Path, Query, Request, State},
+    http::{HeaderMap, StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Json, Response},
     routing::get,
 };
-use crossbeam::atomic::AtomicCell;
-use log::{error, info};
-use mysql::prelude::Queryable;
-use mysql::{Pool, PooledConn};
+use log::{error, info, warn};
 use std::net::SocketAddr;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
 
-use crate::app::{HbdParams, HbdService, HealthService};
+use crate::app::{
+    ApiError, CacheWarmup, HbdError, HbdParams, HbdService, HealthParams, HealthService,
+    LivenessResponse, ReadinessResponse,
+};
+use crate::executor::DbExecutor;
+use crate::health::HealthMonitor;
+use crate::history::{
+    HeartbeatPage, HistoryParams, HistoryService, LatestHeartbeats, LatestParams,
+};
+use crate::logging::{DEBUG_MAC_KEY, LogControl};
+use crate::mdc::WithMdc;
+use crate::metrics::{Counter, HTTP_LATENCY_BUCKETS, MetricsRegistry, RequestTimings};
+use crate::reports::{AvailabilityParams, DeviceAvailability, FleetAvailability, ReportService};
+use crate::request_id::propagate_request_id;
+use crate::retention::RetentionJob;
+use crate::webhooks::WebhookDispatcher;
+use crate::writer::HeartbeatWriter;
 
+/// State shared by every handler
+///
+/// axum clones the state for each request, so everything lives in one
+/// reference-counted core and a clone is just another handle to it.
+#[derive(Clone)]
 pub struct AppState {
-    pub health_count: AtomicCell<u64>,
-    pub hbd_count: AtomicCell<u64>,
+    core: Arc<AppCore>,
+}
+
+pub struct AppCore {
     pub service_name: String,
     pub version: String,
-    pub db_pool: Pool,
+    pub metrics: Arc<MetricsRegistry>,
+    pub health_count: Arc<Counter>,
+    pub hbd_count: Arc<Counter>,
+    /// Requests taking longer are logged with their timings
+    pub slow_request_threshold: Duration,
+    /// How long a cached device authorization is trusted
+    pub device_cache_ttl: Duration,
+    /// Permits for exports running at once
+    pub exports: Arc<Semaphore>,
+    pub db: DbExecutor,
+    pub writer: HeartbeatWriter,
+    pub retention: RetentionJob,
+    pub webhooks: WebhookDispatcher,
+    pub cache: CacheWarmup,
+    pub health: Arc<HealthMonitor>,
+    pub logging: LogControl,
 }
 
-impl Clone for AppState {
-    fn clone(&self) -> Self {
-        Self {
-            health_count: AtomicCell::new(self.health_count.load()),
-            hbd_count: AtomicCell::new(self.hbd_count.load()),
-            service_name: self.service_name.clone(),
-            version: self.version.clone(),
-            db_pool: self.db_pool.clone(),
-        }
+impl Deref for AppState {
+    type Target = AppCore;
+
+    fn deref(&self) -> &AppCore {
+        &self.core
     }
 }
 
 impl AppState {
-    pub fn new(db_pool: Pool) -> Self {
+    pub fn new(core: AppCore) -> Self {
         Self {
-            health_count: AtomicCell::new(0),
-            hbd_count: AtomicCell::new(0),
-            service_name: "axum-health-service".to_string(),
-            version: "0.1.0".to_string(),
-            db_pool,
+            core: Arc::new(core),
         }
     }
 
-    /// Get a database connection from the pool
-    pub fn get_connection(&self) -> Result<PooledConn> {
-        self.db_pool.get_conn().map_err(|e| {
-            error!("Failed to get database connection: {}", e);
-            anyhow::anyhow!("Database connection failed: {}", e)
-        })
-    }
-
     /// Check if database connection is healthy
-    pub fn is_db_healthy(&self) -> bool {
-        match self.get_connection() {
-            Ok(mut conn) => {
-                // Try a simple query to test the connection
-                match conn.query_drop("SELECT 1") {
-                    Ok(_) => true,
-                    Err(e) => {
-                        error!("Database health check failed: {}", e);
-                        false
-                    }
-                }
+    pub async fn is_db_healthy(&self) -> bool {
+        match self.db.run("ping", |store| store.ping()).await {
+            Ok(_) => true,
+            Err(e) => {
+                error!(error:% = e; "Database health check failed");
+                false
             }
-            Err(_) => false,
         }
     }
 }
@@ -74,13 +91,14 @@ impl AppState {
 async fn health(
     State(state): State<AppState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<HealthParams>,
     headers: HeaderMap,
-) -> Json<crate::app::HealthResponse> {
-    info!("Health endpoint called from client: {}", addr);
-    info!("Client IP: {}, Client Port: {}", addr.ip(), addr.port());
+) -> Response {
+    info!(client:% = addr; "Health endpoint called");
+    info!(client_ip:% = addr.ip(), client_port = addr.port(); "Client address");
 
     // Log header information
-    info!("Request headers count: {}", headers.len());
+    info!(header_count = headers.len(); "Request headers");
 
     // Extract User-Agent header
     let user_agent = headers
@@ -89,42 +107,234 @@ async fn health(
         .map(|s| s.to_string());
 
     if let Some(ref ua) = user_agent {
-        info!("User-Agent: {}", ua);
+        info!(user_agent = ua.as_str(); "User-Agent");
     }
 
     // Log some common headers
     for (key, value) in headers.iter() {
         if let Ok(value_str) = value.to_str() {
-            info!("Header {}: {}", key, value_str);
+            info!(header:% = key, value = value_str; "Header");
         }
     }
 
     // Delegate business logic to HealthService
-    let response = HealthService::process_health_check(&state, addr, headers.len(), user_agent);
+    let response = HealthService::process_health_check(
+        &state,
+        addr,
+        headers.len(),
+        user_agent,
+        params.refresh.unwrap_or(false),
+    )
+    .await;
+
+    let status = state.health.status_code(response.status);
+    if prefers_text(&headers) {
+        (
+            status,
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            HealthService::text_line(&response),
+        )
+            .into_response()
+    } else {
+        (status, Json(response)).into_response()
+    }
+}
+
+/// `HEAD /health`: status code only, for load balancers
+async fn health_head(State(state): State<AppState>) -> StatusCode {
+    HealthService::head_status(&state).await
+}
+
+/// Whether the `Accept` header ranks `text/plain` above JSON
+///
+/// JSON wins ties and is the default when the header is missing or names
+/// neither type.
+fn prefers_text(headers: &HeaderMap) -> bool {
+    let Some(accept) = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+
+    let mut json_q: f32 = -1.0;
+    let mut text_q: f32 = -1.0;
+    for entry in accept.split(',') {
+        let mut parts = entry.split(';');
+        let media_type = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match media_type.as_str() {
+            "application/json" | "application/*" => json_q = json_q.max(q),
+            "text/plain" | "text/*" => text_q = text_q.max(q),
+            "*/*" => json_q = json_q.max(q),
+            _ => {}
+        }
+    }
+    text_q > 0.0 && text_q > json_q
+}
+
+/// Liveness probe: no database, no counters, no request logging
+async fn livez() -> Json<LivenessResponse> {
+    Json(HealthService::liveness())
+}
 
-    Json(response)
+/// Readiness probe: 503 until every dependency is usable
+async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<ReadinessResponse>) {
+    let (ready, response) = HealthService::readiness(&state).await;
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(response))
 }
 
 async fn hbd(
     State(state): State<AppState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(timings): Extension<RequestTimings>,
     Query(params): Query<HbdParams>,
-) -> Result<Json<crate::app::HbdResponse>, StatusCode> {
-    info!("HBD endpoint called from client: {}", addr);
+) -> Result<Json<crate::app::HbdResponse>, HbdError> {
+    info!(client:% = addr; "HBD endpoint called");
     info!(
-        "HBD Parameters - ID: {}, MAC: {}, IP: {}, LP: {:?}, TS: {:?}",
-        params.id, params.mac, params.ip, params.lp, params.ts
+        device_id = params.id,
+        mac = params.mac.as_str(),
+        ip = params.ip.as_str(),
+        lp = params.lp,
+        ts = params.ts;
+        "HBD parameters"
     );
 
+    // Heartbeats from devices being debugged log at debug level
+    let debug_mac = state
+        .logging
+        .is_debug_mac(&params.mac)
+        .then(|| params.mac.clone());
+
     // Delegate business logic to HbdService
-   HbdService::process_heartbeat(&state, params, addr)
+    WithMdc::new(
+        DEBUG_MAC_KEY,
+        debug_mac,
+        HbdService::process_heartbeat(&state, params, addr, &timings),
+    )
+    .await
+}
+
+async fn heartbeat_history(
+    State(state): State<AppState>,
+    Path(mac): Path<String>,
+    Query(params): Query<HistoryParams>,
+) -> Result<Json<HeartbeatPage>, ApiError> {
+    info!(mac = mac.as_str(); "Heartbeat history requested");
+    HistoryService::heartbeat_history(&state, mac, params)
+        .await
+        .map(Json)
+}
+
+async fn latest_heartbeats(
+    State(state): State<AppState>,
+    Path(mac): Path<String>,
+    Query(params): Query<LatestParams>,
+) -> Result<Json<LatestHeartbeats>, ApiError> {
+    info!(mac = mac.as_str(); "Latest heartbeats requested");
+    HistoryService::latest_heartbeats(&state, mac, params)
+        .await
+        .map(Json)
+}
+
+async fn device_availability(
+    State(state): State<AppState>,
+    Path(mac): Path<String>,
+    Query(params): Query<AvailabilityParams>,
+) -> Result<Json<DeviceAvailability>, ApiError> {
+    info!(mac = mac.as_str(); "Availability report requested");
+    ReportService::device_availability(&state, mac, params)
+        .await
+        .map(Json)
 }
 
-pub fn create_router(db_pool: Pool) -> Router {
-    let state = AppState::new(db_pool);
+async fn fleet_availability(
+    State(state): State<AppState>,
+    Query(params): Query<AvailabilityParams>,
+) -> Result<Json<FleetAvailability>, ApiError> {
+    info!("Fleet availability report requested");
+    ReportService::fleet_availability(&state, params)
+        .await
+        .map(Json)
+}
 
+pub fn create_router(state: AppState) -> Router {
     Router::new()
-        .route("/health", get(health))
+        .route("/health", get(health).head(health_head))
+        .route("/livez", get(livez))
+        .route("/readyz", get(readyz))
         .route("/hbd", get(hbd))
+        .route("/devices/:mac/heartbeats", get(heartbeat_history))
+        .route("/devices/:mac/heartbeats/latest", get(latest_heartbeats))
+        .route("/devices/:mac/availability", get(device_availability))
+        .route("/reports/availability", get(fleet_availability))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            track_requests,
+        ))
+        .layer(middleware::from_fn(propagate_request_id))
         .with_state(state)
 }
+
+/// Count and time every request by matched route, logging slow ones
+///
+/// Handlers record where the time went in the `RequestTimings` extension.
+async fn track_requests(
+    State(state): State<AppState>,
+    matched_path: Option<MatchedPath>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let route = matched_path
+        .as_ref()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let method = request.method().clone();
+    let uri = request.uri().clone();
+    let timings = RequestTimings::default();
+    request.extensions_mut().insert(timings.clone());
+
+    let started = Instant::now();
+    let response = next.run(request).await;
+    let elapsed = started.elapsed();
+
+    let status = response.status().as_u16().to_string();
+    state
+        .metrics
+        .counter(
+            "http_requests_total",
+            "HTTP requests by route and status",
+            &[("route", &route), ("status", &status)],
+        )
+        .inc();
+    state
+        .metrics
+        .histogram(
+            "http_request_duration_seconds",
+            "Time to produce a response, by route",
+            HTTP_LATENCY_BUCKETS,
+            &[("route", &route)],
+        )
+        .observe(elapsed.as_secs_f64());
+
+    if elapsed >= state.slow_request_threshold {
+        warn!(
+            method:% = method,
+            uri:% = uri,
+            route = route.as_str(),
+            status = status.as_str(),
+            duration_ms = elapsed.as_millis() as u64,
+            timings:% = timings.summary();
+            "Slow request"
+        );
+    }
+    response
+}
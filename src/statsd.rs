@@ -0,0 +1,358 @@
+use log::{info, warn};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+use crate::config::{StatsdConfig, StatsdFlavor};
+use crate::metrics::Labels;
+
+/// Handle for pushing metric lines to a StatsD agent
+///
+/// Lines are queued without waiting and sent in batches from a background
+/// task, so a slow or unreachable agent never holds up a request. Counter
+/// increments are summed in place and sent once per flush.
+#[derive(Clone)]
+pub struct StatsdSink {
+    tx: mpsc::Sender<String>,
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    flavor: StatsdFlavor,
+    prefix: String,
+    /// `service:..,version:..`, sent with every DogStatsD line
+    base_tags: String,
+    dropped: AtomicU64,
+    /// Every counter handed out, read and reset on each flush
+    counters: Mutex<Vec<Arc<PendingCount>>>,
+}
+
+/// Increments of one counter series not yet sent
+struct PendingCount {
+    name: String,
+    tags: String,
+    pending: AtomicU64,
+}
+
+impl Inner {
+    /// One `|c` line per counter that moved since the last call
+    fn take_counts(&self) -> Vec<String> {
+        self.counters
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|count| match count.pending.swap(0, Ordering::Relaxed) {
+                0 => None,
+                value => Some(format!("{}:{}|c{}", count.name, value, count.tags)),
+            })
+            .collect()
+    }
+}
+
+impl StatsdSink {
+    pub fn spawn(config: StatsdConfig, service_name: &str, version: &str) -> Self {
+        let (tx, rx) = mpsc::channel(config.queue_capacity.max(1));
+        let sink = Self {
+            tx,
+            inner: Arc::new(Inner {
+                flavor: config.flavor,
+                prefix: config.prefix.clone(),
+                base_tags: format!(
+                    "service:{},version:{}",
+                    sanitize_tag(service_name),
+                    sanitize_tag(version)
+                ),
+                dropped: AtomicU64::new(0),
+                counters: Mutex::default(),
+            }),
+        };
+        info!(
+            "Pushing metrics to StatsD at {}:{} ({:?}), flush every {}ms",
+            config.host, config.port, config.flavor, config.flush_interval_ms
+        );
+        tokio::spawn(run(config, rx, sink.inner.clone()));
+        sink
+    }
+
+    /// Timer for one labelled series
+    pub fn series(&self, name: &str, labels: &Labels) -> StatsdSeries {
+        let (name, tags) = self.format(name, labels);
+        StatsdSeries {
+            sink: self.clone(),
+            name,
+            tags,
+        }
+    }
+
+    /// Counter for one labelled series, sent as one total per flush
+    pub fn counter(&self, name: &str, labels: &Labels) -> StatsdCounter {
+        let (name, tags) = self.format(name, labels);
+        let count = Arc::new(PendingCount {
+            name,
+            tags,
+            pending: AtomicU64::new(0),
+        });
+        self.inner.counters.lock().unwrap().push(count.clone());
+        StatsdCounter { count }
+    }
+
+    /// Pre-formatted name and tags for one labelled series
+    fn format(&self, name: &str, labels: &Labels) -> (String, String) {
+        match self.inner.flavor {
+            StatsdFlavor::Dogstatsd => {
+                let mut tags = self.inner.base_tags.clone();
+                for (key, value) in labels {
+                    tags.push(',');
+                    tags.push_str(&sanitize_tag(key));
+                    tags.push(':');
+                    tags.push_str(&sanitize_tag(value));
+                }
+                (
+                    format!("{}{}", self.inner.prefix, name),
+                    format!("|#{}", tags),
+                )
+            }
+            StatsdFlavor::Statsd => {
+                let mut full = format!("{}{}", self.inner.prefix, name);
+                for (_, value) in labels {
+                    full.push('.');
+                    full.push_str(&sanitize_name(value));
+                }
+                (full, String::new())
+            }
+        }
+    }
+
+    fn send(&self, line: String) {
+        if self.tx.try_send(line).is_err() {
+            let dropped = self.inner.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_power_of_two() {
+                warn!("StatsD queue is full, {} metrics dropped so far", dropped);
+            }
+        }
+    }
+}
+
+/// One timer series as StatsD sees it; histograms hold one of these
+#[derive(Clone)]
+pub struct StatsdSeries {
+    sink: StatsdSink,
+    name: String,
+    tags: String,
+}
+
+impl StatsdSeries {
+    /// Timers are in milliseconds, histograms in seconds
+    pub fn timing(&self, seconds: f64) {
+        self.sink.send(format!(
+            "{}:{:.3}|ms{}",
+            self.name,
+            seconds * 1000.0,
+            self.tags
+        ));
+    }
+}
+
+/// One counter series as StatsD sees it; increments wait for the next flush
+#[derive(Clone)]
+pub struct StatsdCounter {
+    count: Arc<PendingCount>,
+}
+
+impl StatsdCounter {
+    pub fn add(&self, value: u64) {
+        self.count.pending.fetch_add(value, Ordering::Relaxed);
+    }
+}
+
+/// Prometheus-style names end in a unit; StatsD timers are always milliseconds
+pub fn timer_name(name: &str) -> &str {
+    name.strip_suffix("_seconds").unwrap_or(name)
+}
+
+async fn run(config: StatsdConfig, mut rx: mpsc::Receiver<String>, inner: Arc<Inner>) {
+    let max_packet = config.max_packet_bytes.max(64);
+    let mut flush = tokio::time::interval(Duration::from_millis(config.flush_interval_ms.max(10)));
+    let mut batch = String::with_capacity(max_packet);
+    let mut agent = Agent {
+        target: format!("{}:{}", config.host, config.port),
+        connection: None,
+        failures: 0,
+        max_packet,
+    };
+
+    loop {
+        tokio::select! {
+            line = rx.recv() => {
+                let Some(line) = line else { break };
+                agent.push(&mut batch, &line).await;
+            }
+            _ = flush.tick() => {
+                for line in inner.take_counts() {
+                    agent.push(&mut batch, &line).await;
+                }
+                agent.send(&mut batch).await;
+            }
+        }
+    }
+    for line in inner.take_counts() {
+        agent.push(&mut batch, &line).await;
+    }
+    agent.send(&mut batch).await;
+    info!(
+        "StatsD sink stopped, {} metrics dropped",
+        inner.dropped.load(Ordering::Relaxed)
+    );
+}
+
+/// Where batches go; the host is resolved again after a failed send
+struct Agent {
+    target: String,
+    /// Resolved agent address and a socket of the same address family
+    connection: Option<(SocketAddr, UdpSocket)>,
+    failures: u64,
+    max_packet: usize,
+}
+
+impl Agent {
+    /// Add `line` to the batch, sending the batch first if it would not fit
+    async fn push(&mut self, batch: &mut String, line: &str) {
+        if !batch.is_empty() && batch.len() + 1 + line.len() > self.max_packet {
+            self.send(batch).await;
+        }
+        if !batch.is_empty() {
+            batch.push('\n');
+        }
+        batch.push_str(line);
+    }
+
+    async fn send(&mut self, batch: &mut String) {
+        if batch.is_empty() {
+            return;
+        }
+        let result = match self.connection.take() {
+            Some(connection) => Ok(connection),
+            None => self.connect().await,
+        };
+        let result = match result {
+            Ok((addr, socket)) => {
+                let sent = socket.send_to(batch.as_bytes(), addr).await.map(|_| ());
+                if sent.is_ok() {
+                    self.connection = Some((addr, socket));
+                }
+                sent
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            self.failures += 1;
+            if self.failures.is_power_of_two() {
+                warn!(
+                    "Failed to send metrics to StatsD at {} ({} failures): {}",
+                    self.target, self.failures, e
+                );
+            }
+        }
+        batch.clear();
+    }
+
+    /// Resolve the agent and bind a socket that can reach it
+    async fn connect(&self) -> std::io::Result<(SocketAddr, UdpSocket)> {
+        let addr = tokio::net::lookup_host(&self.target)
+            .await?
+            .next()
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "host resolved to no addresses",
+                )
+            })?;
+        let local: SocketAddr = if addr.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        Ok((addr, UdpSocket::bind(local).await?))
+    }
+}
+
+/// Tags may not contain the line, field, or tag separators
+fn sanitize_tag(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '|' | ',' | '#' | '\n' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Keep plain StatsD names to characters every agent accepts
+fn sanitize_name(value: &str) -> String {
+    value
+        .trim_matches('/')
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn agent(bind: &str) -> Option<(UdpSocket, StatsdConfig)> {
+        let socket = UdpSocket::bind(bind).await.ok()?;
+        let addr = socket.local_addr().ok()?;
+        let config = StatsdConfig {
+            enabled: true,
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            flavor: StatsdFlavor::Statsd,
+            flush_interval_ms: 20,
+            ..StatsdConfig::default()
+        };
+        Some((socket, config))
+    }
+
+    async fn receive(socket: &UdpSocket) -> String {
+        let mut buf = [0u8; 2048];
+        let len = tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buf))
+            .await
+            .expect("no packet from the sink")
+            .unwrap();
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    #[tokio::test]
+    async fn counter_increments_are_summed_per_flush() {
+        let (socket, config) = agent("127.0.0.1:0").await.unwrap();
+        let sink = StatsdSink::spawn(config, "svc", "1.0");
+        let counter = sink.counter("hbd_total", &vec![("outcome".into(), "accepted".into())]);
+        for _ in 0..5 {
+            counter.add(1);
+        }
+
+        assert_eq!(receive(&socket).await, "hbd_total.accepted:5|c");
+    }
+
+    #[tokio::test]
+    async fn sends_to_an_ipv6_agent() {
+        // Skipped where the host has no IPv6 loopback
+        let Some((socket, config)) = agent("[::1]:0").await else {
+            return;
+        };
+        let sink = StatsdSink::spawn(config, "svc", "1.0");
+        sink.series("request_duration", &Vec::new()).timing(0.5);
+
+        assert_eq!(receive(&socket).await, "request_duration:500.000|ms");
+    }
+}
@@ -0,0 +1,993 @@
+use anyhow::Result;
+use chrono::{DateTime, DurationRound, Utc};
+use log::error;
+use mysql::prelude::Queryable;
+use mysql::{Pool, PooledConn};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+/// Why a device was or was not authorized
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthorizationReason {
+    Active,
+    Squelched,
+    UnknownDevice,
+    InactiveDevice,
+    InactiveAccount,
+    DbError,
+}
+
+impl AuthorizationReason {
+    /// Map the `p_msg` output of `is_device_active` to a reason code
+    pub fn from_procedure_message(message: &str) -> Option<Self> {
+        match message.trim().to_ascii_lowercase().as_str() {
+            "active" => Some(Self::Active),
+            "squelched" => Some(Self::Squelched),
+            "unknown device" => Some(Self::UnknownDevice),
+            "inactive device" => Some(Self::InactiveDevice),
+            "inactive account" => Some(Self::InactiveAccount),
+            _ => None,
+        }
+    }
+
+    /// Human-readable explanation used when the store gave no message
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::Active => "device is active",
+            Self::Squelched => "device is squelched",
+            Self::UnknownDevice => "device is not registered",
+            Self::InactiveDevice => "device is deactivated",
+            Self::InactiveAccount => "device account is inactive",
+            Self::DbError => "device authorization could not be checked",
+        }
+    }
+}
+
+/// Result of looking a device up in the authorization store
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceAuthorization {
+    pub authorized: bool,
+    pub squelched: bool,
+    pub account_id: Option<i32>,
+    pub reason: AuthorizationReason,
+    /// Free-text explanation, e.g. the stored procedure's message
+    pub message: Option<String>,
+}
+
+impl DeviceAuthorization {
+    /// Authorization used when a device is unknown or the lookup failed
+    pub fn denied(reason: AuthorizationReason, message: Option<String>) -> Self {
+        Self {
+            authorized: false,
+            squelched: true,
+            account_id: None,
+            reason,
+            message,
+        }
+    }
+}
+
+/// A single heartbeat as written to storage
+#[derive(Clone, Debug, Serialize)]
+pub struct HeartbeatRecord {
+    pub device_id: i32,
+    pub mac: String,
+    pub reported_ip: String,
+    pub public_ip: String,
+    pub lp: Option<i32>,
+    pub device_ts: Option<i64>,
+    pub received_at: DateTime<Utc>,
+}
+
+/// A heartbeat read back from storage, with its row id
+#[derive(Clone, Debug, Serialize)]
+pub struct StoredHeartbeat {
+    pub id: u64,
+    #[serde(flatten)]
+    pub record: HeartbeatRecord,
+}
+
+/// One page of a device's heartbeat history
+///
+/// Rows are returned in id order, starting after `after_id`, with
+/// `received_at` in `[from, to)`.
+#[derive(Clone, Debug)]
+pub struct HeartbeatQuery {
+    pub mac: String,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub after_id: Option<u64>,
+    pub limit: usize,
+}
+
+/// A stretch of time a device was heard from, for availability math
+///
+/// A raw heartbeat is a span of a single instant. A rolled-up hour spans its
+/// first to last heartbeat, since the gaps inside it are no longer known.
+#[derive(Clone, Debug)]
+pub struct ActivitySpan {
+    pub mac: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub heartbeat_count: u64,
+    /// Long poll the device reported; the largest one for a rolled-up hour
+    pub lp: Option<i32>,
+}
+
+/// A registered device
+#[derive(Clone, Debug, Serialize)]
+pub struct DeviceRecord {
+    pub mac: String,
+    pub account_id: Option<i32>,
+    pub active: bool,
+    pub squelched: bool,
+}
+
+/// Per-device summary of one hour of heartbeats
+#[derive(Clone, Debug, Serialize)]
+pub struct HourlySummary {
+    pub mac: String,
+    pub hour_start: DateTime<Utc>,
+    pub device_id: i32,
+    pub heartbeat_count: u64,
+    pub max_lp: Option<i32>,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub distinct_reported_ips: u64,
+    pub distinct_public_ips: u64,
+}
+
+/// Row filters shared by the export endpoints and CLI
+#[derive(Clone, Debug, Default)]
+pub struct ExportFilter {
+    /// Heartbeats received at or after this time
+    pub from: Option<DateTime<Utc>>,
+    /// Heartbeats received before this time
+    pub to: Option<DateTime<Utc>>,
+    pub account_id: Option<i32>,
+    pub mac_prefix: Option<String>,
+}
+
+/// Callback invoked once per exported row; returning `Err` stops the export
+pub type RowSink<'a, T> = &'a mut dyn FnMut(T) -> Result<()>;
+
+/// Storage operations used by the HTTP services
+///
+/// Connection failures are returned as `Err`; everything the store was able to
+/// answer (including "unknown device") is returned as `Ok`.
+pub trait DeviceStore: Send + Sync {
+    /// Look up whether a device may report heartbeats
+    fn authorize_device(&self, mac: &str) -> Result<DeviceAuthorization>;
+
+    /// Persist a single heartbeat
+    fn persist_heartbeat(&self, heartbeat: &HeartbeatRecord) -> Result<()>;
+
+    /// Read a page of a device's heartbeat history
+    fn heartbeat_history(&self, query: &HeartbeatQuery) -> Result<Vec<StoredHeartbeat>>;
+
+    /// Read a device's most recent heartbeats, newest first
+    fn latest_heartbeats(&self, mac: &str, limit: usize) -> Result<Vec<StoredHeartbeat>>;
+
+    /// Stream device activity in `[from, to)` to `sink`, by MAC and then time
+    ///
+    /// Hours already rolled up into `heartbeat_hourly` come from there, one
+    /// span per device-hour, and later ones from the raw heartbeats. `mac`
+    /// limits the stream to one device.
+    fn activity_spans(
+        &self,
+        mac: Option<&str>,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        sink: RowSink<'_, ActivitySpan>,
+    ) -> Result<()>;
+
+    /// Every registered device
+    fn list_devices(&self) -> Result<Vec<DeviceRecord>>;
+
+    /// MACs of every device `is_device_active` accepts unsquelched, in one query
+    ///
+    /// Applies the procedure's device and account rules set-wise, for warming
+    /// the device cache without a call per device.
+    fn list_authorized_devices(&self) -> Result<Vec<String>>;
+
+    /// Stream every heartbeat matching `filter` to `sink`, in id order
+    ///
+    /// Rows are handed over one at a time as they are read, never collected.
+    fn export_heartbeats(
+        &self,
+        filter: &ExportFilter,
+        sink: RowSink<'_, StoredHeartbeat>,
+    ) -> Result<()>;
+
+    /// Stream every device matching `filter` to `sink`, ignoring the time range
+    fn export_devices(&self, filter: &ExportFilter, sink: RowSink<'_, DeviceRecord>) -> Result<()>;
+
+    /// Receive time of the oldest raw heartbeat, if there is any
+    fn oldest_heartbeat(&self) -> Result<Option<DateTime<Utc>>>;
+
+    /// Summarize raw heartbeats received in `[from, to)` into hourly rows
+    ///
+    /// Both bounds are whole hours. Hours that already have a summary are left
+    /// alone, so this is safe to re-run after a partially completed retention
+    /// pass. Returns the number of summary rows written.
+    fn rollup_heartbeats(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<u64>;
+
+    /// Delete at most `limit` raw heartbeats received before `cutoff`
+    fn delete_heartbeats_before(&self, cutoff: DateTime<Utc>, limit: usize) -> Result<u64>;
+
+    /// Cheap round trip used to check connectivity
+    fn ping(&self) -> Result<()>;
+
+    /// Connection pool usage, for stores that have a pool
+    ///
+    /// Must not block: it is called directly from async handlers.
+    fn pool_stats(&self) -> Option<PoolStats> {
+        None
+    }
+}
+
+/// Connection pool usage reported by `/health`
+#[derive(Clone, Debug, Serialize)]
+pub struct PoolStats {
+    pub max_connections: usize,
+    pub min_idle: usize,
+    /// Connections currently checked out by a worker
+    pub in_use: usize,
+    /// Connections a worker could check out right now, whether or not the
+    /// pool has opened them yet (`max_connections - in_use`)
+    pub available: usize,
+    /// Workers waiting for a connection to be returned
+    pub waiting: usize,
+}
+
+/// Pool constraints the MySQL store was built with
+#[derive(Clone, Copy, Debug)]
+pub struct PoolLimits {
+    pub min_idle: usize,
+    pub max_connections: usize,
+    /// How long a worker waits for a free connection
+    pub acquire_timeout: Duration,
+}
+
+#[derive(Default)]
+struct PoolCounters {
+    in_use: AtomicUsize,
+    waiting: AtomicUsize,
+}
+
+/// Pooled connection that keeps `PoolCounters::in_use` up to date
+struct TrackedConn<'a> {
+    conn: PooledConn,
+    counters: &'a PoolCounters,
+}
+
+impl Deref for TrackedConn<'_> {
+    type Target = PooledConn;
+
+    fn deref(&self) -> &PooledConn {
+        &self.conn
+    }
+}
+
+impl DerefMut for TrackedConn<'_> {
+    fn deref_mut(&mut self) -> &mut PooledConn {
+        &mut self.conn
+    }
+}
+
+impl Drop for TrackedConn<'_> {
+    fn drop(&mut self) {
+        self.counters.in_use.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// `DeviceStore` backed by the MySQL connection pool
+pub struct MySqlDeviceStore {
+    pool: Pool,
+    limits: PoolLimits,
+    counters: PoolCounters,
+}
+
+impl MySqlDeviceStore {
+    pub fn new(pool: Pool, limits: PoolLimits) -> Self {
+        Self {
+            pool,
+            limits,
+            counters: PoolCounters::default(),
+        }
+    }
+
+    /// Get a database connection from the pool
+    fn get_connection(&self) -> Result<TrackedConn<'_>> {
+        self.counters.waiting.fetch_add(1, Ordering::Relaxed);
+        let conn = self.pool.try_get_conn(self.limits.acquire_timeout);
+        self.counters.waiting.fetch_sub(1, Ordering::Relaxed);
+        let conn = conn.map_err(|e| {
+            error!("Failed to get database connection: {}", e);
+            anyhow::anyhow!("Database connection failed: {}", e)
+        })?;
+
+        self.counters.in_use.fetch_add(1, Ordering::Relaxed);
+
+        Ok(TrackedConn {
+            conn,
+            counters: &self.counters,
+        })
+    }
+}
+
+impl DeviceStore for MySqlDeviceStore {
+    fn authorize_device(&self, mac: &str) -> Result<DeviceAuthorization> {
+        let mut conn = self.get_connection()?;
+
+        // Call the stored procedure
+        let result: Result<Vec<mysql::Row>, mysql::Error> =
+            conn.exec("CALL is_device_active(?, @msg)", (mac,));
+
+        // A failed call says nothing about the device, so it is an error for
+        // the caller and the circuit breaker rather than a denial
+        let mut rows = match result {
+            Ok(rows) => rows,
+            Err(e) => {
+                error!("is_device_active failed for MAC {}: {}", mac, e);
+                return Err(anyhow::anyhow!("is_device_active failed: {}", e));
+            }
+        };
+
+        // Handle the @msg output parameter properly
+        let message: Option<String> = match conn.query_first("SELECT @msg") {
+            Ok(message) => message.flatten(),
+            Err(e) => {
+                error!("Failed to read @msg for MAC {}: {}", mac, e);
+                None
+            }
+        };
+        let reason = message
+            .as_deref()
+            .and_then(AuthorizationReason::from_procedure_message);
+
+        match rows.pop() {
+            Some(row) => {
+                let (account_id, squelch): (Option<i32>, i32) = mysql::from_row(row);
+                let squelched = squelch != 0;
+                Ok(DeviceAuthorization {
+                    authorized: true,
+                    squelched,
+                    account_id,
+                    reason: reason.unwrap_or(if squelched {
+                        AuthorizationReason::Squelched
+                    } else {
+                        AuthorizationReason::Active
+                    }),
+                    message,
+                })
+            }
+            None => Ok(DeviceAuthorization::denied(
+                reason.unwrap_or(AuthorizationReason::UnknownDevice),
+                message,
+            )),
+        }
+    }
+
+    fn persist_heartbeat(&self, heartbeat: &HeartbeatRecord) -> Result<()> {
+        let mut conn = self.get_connection()?;
+        conn.exec_drop(
+            "INSERT INTO heartbeats (device_id, mac_address, reported_ip, public_ip, lp, device_ts, received_at) \
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+            (
+                heartbeat.device_id,
+                &heartbeat.mac,
+                &heartbeat.reported_ip,
+                &heartbeat.public_ip,
+                heartbeat.lp,
+                heartbeat.device_ts,
+                heartbeat.received_at.naive_utc(),
+            ),
+        )?;
+        Ok(())
+    }
+
+    fn heartbeat_history(&self, query: &HeartbeatQuery) -> Result<Vec<StoredHeartbeat>> {
+        let mut conn = self.get_connection()?;
+        let rows: Vec<HeartbeatRow> = conn.exec(
+            format!(
+                "SELECT {} FROM heartbeats \
+                 WHERE mac_address = ? AND received_at >= ? AND received_at < ? AND id > ? \
+                 ORDER BY id LIMIT ?",
+                HEARTBEAT_COLUMNS
+            ),
+            (
+                &query.mac,
+                query.from.naive_utc(),
+                query.to.naive_utc(),
+                query.after_id.unwrap_or(0),
+                query.limit as u64,
+            ),
+        )?;
+        Ok(rows.into_iter().map(stored_heartbeat_from_row).collect())
+    }
+
+    fn latest_heartbeats(&self, mac: &str, limit: usize) -> Result<Vec<StoredHeartbeat>> {
+        let mut conn = self.get_connection()?;
+        let rows: Vec<HeartbeatRow> = conn.exec(
+            format!(
+                "SELECT {} FROM heartbeats WHERE mac_address = ? ORDER BY id DESC LIMIT ?",
+                HEARTBEAT_COLUMNS
+            ),
+            (mac, limit as u64),
+        )?;
+        Ok(rows.into_iter().map(stored_heartbeat_from_row).collect())
+    }
+
+    fn activity_spans(
+        &self,
+        mac: Option<&str>,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        sink: RowSink<'_, ActivitySpan>,
+    ) -> Result<()> {
+        let mut conn = self.get_connection()?;
+
+        // The retention job rolls hours up oldest first, so every hour before
+        // the latest summary is complete there. Their raw rows may not be
+        // deleted yet and must not be counted twice.
+        let latest_hour: Option<Option<chrono::NaiveDateTime>> =
+            conn.query_first("SELECT MAX(hour_start) FROM heartbeat_hourly")?;
+        let rolled_up_until = latest_hour
+            .flatten()
+            .map(|hour| hour.and_utc() + chrono::Duration::hours(1));
+
+        let mac_filter = if mac.is_some() {
+            " AND mac_address = ?"
+        } else {
+            ""
+        };
+        let mut sql = String::new();
+        let mut params: Vec<mysql::Value> = Vec::new();
+        let mut raw_from = from;
+        if let Some(until) = rolled_up_until.filter(|until| *until > from) {
+            sql.push_str(&format!(
+                "SELECT mac_address, first_seen, last_seen, heartbeat_count, max_lp \
+                 FROM heartbeat_hourly WHERE hour_start >= ? AND hour_start < ?{} \
+                 UNION ALL ",
+                mac_filter
+            ));
+            params.push(hour_start(from).naive_utc().into());
+            params.push(until.min(to).naive_utc().into());
+            params.extend(mac.map(mysql::Value::from));
+            raw_from = until;
+        }
+        sql.push_str(&format!(
+            "SELECT mac_address, received_at AS first_seen, received_at AS last_seen, \
+                    1 AS heartbeat_count, lp AS max_lp \
+             FROM heartbeats WHERE received_at >= ? AND received_at < ?{} \
+             ORDER BY mac_address, first_seen",
+            mac_filter
+        ));
+        params.push(raw_from.naive_utc().into());
+        params.push(to.naive_utc().into());
+        params.extend(mac.map(mysql::Value::from));
+
+        for row in conn.exec_iter(sql, params)? {
+            let (mac, first_seen, last_seen, heartbeat_count, lp): (
+                String,
+                chrono::NaiveDateTime,
+                chrono::NaiveDateTime,
+                u64,
+                Option<i32>,
+            ) = mysql::from_row(row?);
+            sink(ActivitySpan {
+                mac,
+                first_seen: first_seen.and_utc(),
+                last_seen: last_seen.and_utc(),
+                heartbeat_count,
+                lp,
+            })?;
+        }
+        Ok(())
+    }
+
+    fn list_devices(&self) -> Result<Vec<DeviceRecord>> {
+        let mut conn = self.get_connection()?;
+        let devices = conn.query_map(
+            "SELECT mac_address, account_id, active, squelch FROM devices ORDER BY mac_address",
+            |(mac, account_id, active, squelch): (String, Option<i32>, bool, bool)| DeviceRecord {
+                mac,
+                account_id,
+                active,
+                squelched: squelch,
+            },
+        )?;
+        Ok(devices)
+    }
+
+    fn list_authorized_devices(&self) -> Result<Vec<String>> {
+        let mut conn = self.get_connection()?;
+        let macs = conn.query(
+            "SELECT d.mac_address FROM devices d \
+             LEFT JOIN accounts a ON a.id = d.account_id \
+             WHERE d.active <> 0 AND d.squelch = 0 AND COALESCE(a.active, 1) <> 0 \
+             ORDER BY d.mac_address",
+        )?;
+        Ok(macs)
+    }
+
+    fn export_heartbeats(
+        &self,
+        filter: &ExportFilter,
+        sink: RowSink<'_, StoredHeartbeat>,
+    ) -> Result<()> {
+        let mut sql = String::from(
+            "SELECT h.id, h.device_id, h.mac_address, h.reported_ip, h.public_ip, h.lp, \
+                    h.device_ts, h.received_at \
+             FROM heartbeats h",
+        );
+        let mut params: Vec<mysql::Value> = Vec::new();
+        if filter.account_id.is_some() {
+            sql.push_str(" JOIN devices d ON d.mac_address = h.mac_address");
+        }
+        sql.push_str(" WHERE 1 = 1");
+        if let Some(from) = filter.from {
+            sql.push_str(" AND h.received_at >= ?");
+            params.push(from.naive_utc().into());
+        }
+        if let Some(to) = filter.to {
+            sql.push_str(" AND h.received_at < ?");
+            params.push(to.naive_utc().into());
+        }
+        if let Some(account_id) = filter.account_id {
+            sql.push_str(" AND d.account_id = ?");
+            params.push(account_id.into());
+        }
+        if let Some(prefix) = &filter.mac_prefix {
+            sql.push_str(" AND h.mac_address LIKE ?");
+            params.push(like_prefix(prefix).into());
+        }
+        sql.push_str(" ORDER BY h.id");
+
+        let mut conn = self.get_connection()?;
+        for row in conn.exec_iter(sql, params)? {
+            let row: HeartbeatRow = mysql::from_row(row?);
+            sink(stored_heartbeat_from_row(row))?;
+        }
+        Ok(())
+    }
+
+    fn export_devices(&self, filter: &ExportFilter, sink: RowSink<'_, DeviceRecord>) -> Result<()> {
+        let mut sql = String::from(
+            "SELECT mac_address, account_id, active, squelch FROM devices WHERE 1 = 1",
+        );
+        let mut params: Vec<mysql::Value> = Vec::new();
+        if let Some(account_id) = filter.account_id {
+            sql.push_str(" AND account_id = ?");
+            params.push(account_id.into());
+        }
+        if let Some(prefix) = &filter.mac_prefix {
+            sql.push_str(" AND mac_address LIKE ?");
+            params.push(like_prefix(prefix).into());
+        }
+        sql.push_str(" ORDER BY mac_address");
+
+        let mut conn = self.get_connection()?;
+        for row in conn.exec_iter(sql, params)? {
+            let (mac, account_id, active, squelch): (String, Option<i32>, bool, bool) =
+                mysql::from_row(row?);
+            sink(DeviceRecord {
+                mac,
+                account_id,
+                active,
+                squelched: squelch,
+            })?;
+        }
+        Ok(())
+    }
+
+    fn oldest_heartbeat(&self) -> Result<Option<DateTime<Utc>>> {
+        let mut conn = self.get_connection()?;
+        let oldest: Option<Option<chrono::NaiveDateTime>> =
+            conn.query_first("SELECT MIN(received_at) FROM heartbeats")?;
+        Ok(oldest.flatten().map(|at| at.and_utc()))
+    }
+
+    fn rollup_heartbeats(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<u64> {
+        let mut conn = self.get_connection()?;
+        conn.exec_drop(
+            "INSERT IGNORE INTO heartbeat_hourly \
+                 (mac_address, hour_start, device_id, heartbeat_count, max_lp, first_seen, \
+                  last_seen, distinct_reported_ips, distinct_public_ips) \
+             SELECT mac_address, DATE_FORMAT(received_at, '%Y-%m-%d %H:00:00') AS hour_start, \
+                    MAX(device_id), COUNT(*), MAX(lp), MIN(received_at), MAX(received_at), \
+                    COUNT(DISTINCT reported_ip), COUNT(DISTINCT public_ip) \
+             FROM heartbeats WHERE received_at >= ? AND received_at < ? \
+             GROUP BY mac_address, hour_start",
+            (from.naive_utc(), to.naive_utc()),
+        )?;
+        Ok(conn.affected_rows())
+    }
+
+    fn delete_heartbeats_before(&self, cutoff: DateTime<Utc>, limit: usize) -> Result<u64> {
+        let mut conn = self.get_connection()?;
+        conn.exec_drop(
+            "DELETE FROM heartbeats WHERE received_at < ? ORDER BY id LIMIT ?",
+            (cutoff.naive_utc(), limit as u64),
+        )?;
+        Ok(conn.affected_rows())
+    }
+
+    fn ping(&self) -> Result<()> {
+        let mut conn = self.get_connection()?;
+        conn.query_drop("SELECT 1")?;
+        Ok(())
+    }
+
+    fn pool_stats(&self) -> Option<PoolStats> {
+        // The mysql pool does not expose how many connections it holds open,
+        // so only what this store checks out or waits for is reported
+        let in_use = self.counters.in_use.load(Ordering::Relaxed);
+        Some(PoolStats {
+            max_connections: self.limits.max_connections,
+            min_idle: self.limits.min_idle,
+            in_use,
+            available: self.limits.max_connections.saturating_sub(in_use),
+            waiting: self.counters.waiting.load(Ordering::Relaxed),
+        })
+    }
+}
+
+const HEARTBEAT_COLUMNS: &str =
+    "id, device_id, mac_address, reported_ip, public_ip, lp, device_ts, received_at";
+
+type HeartbeatRow = (
+    u64,
+    i32,
+    String,
+    String,
+    String,
+    Option<i32>,
+    Option<i64>,
+    chrono::NaiveDateTime,
+);
+
+fn stored_heartbeat_from_row(row: HeartbeatRow) -> StoredHeartbeat {
+    let (id, device_id, mac, reported_ip, public_ip, lp, device_ts, received_at) = row;
+    StoredHeartbeat {
+        id,
+        record: HeartbeatRecord {
+            device_id,
+            mac,
+            reported_ip,
+            public_ip,
+            lp,
+            device_ts,
+            received_at: received_at.and_utc(),
+        },
+    }
+}
+
+/// Start of the hour `at` falls in
+fn hour_start(at: DateTime<Utc>) -> DateTime<Utc> {
+    at.duration_trunc(chrono::Duration::hours(1)).unwrap_or(at)
+}
+
+/// `LIKE` pattern matching values that start with `prefix`
+fn like_prefix(prefix: &str) -> String {
+    let escaped = prefix
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("{}%", escaped)
+}
+
+/// `DeviceStore` kept entirely in process memory
+///
+/// Used for local development without MySQL and for exercising the services
+/// without a database.
+#[derive(Default)]
+pub struct InMemoryDeviceStore {
+    devices: RwLock<HashMap<String, DeviceAuthorization>>,
+    heartbeats: Mutex<Vec<StoredHeartbeat>>,
+    hourly: Mutex<HashMap<(String, DateTime<Utc>), HourlySummary>>,
+    /// Fail authorization lookups as an unreachable database would
+    unavailable: AtomicBool,
+}
+
+impl InMemoryDeviceStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a device so that `authorize_device` recognizes it
+    pub fn register_device(&self, mac: &str, account_id: Option<i32>, squelched: bool) {
+        let authorization = DeviceAuthorization {
+            authorized: true,
+            squelched,
+            account_id,
+            reason: if squelched {
+                AuthorizationReason::Squelched
+            } else {
+                AuthorizationReason::Active
+            },
+            message: None,
+        };
+        self.devices
+            .write()
+            .unwrap()
+            .insert(mac.to_string(), authorization);
+    }
+
+    #[cfg(test)]
+    pub fn set_unavailable(&self, unavailable: bool) {
+        self.unavailable.store(unavailable, Ordering::Relaxed);
+    }
+
+    /// Mark a registered device inactive, as an operator would in the database
+    #[cfg(test)]
+    pub fn deactivate_device(&self, mac: &str) {
+        self.devices.write().unwrap().insert(
+            mac.to_string(),
+            DeviceAuthorization::denied(AuthorizationReason::InactiveDevice, None),
+        );
+    }
+}
+
+impl DeviceStore for InMemoryDeviceStore {
+    fn authorize_device(&self, mac: &str) -> Result<DeviceAuthorization> {
+        if self.unavailable.load(Ordering::Relaxed) {
+            return Err(anyhow::anyhow!("in-memory store is unavailable"));
+        }
+        let devices = self.devices.read().unwrap();
+        Ok(devices.get(mac).cloned().unwrap_or_else(|| {
+            DeviceAuthorization::denied(
+                AuthorizationReason::UnknownDevice,
+                Some("unknown device".to_string()),
+            )
+        }))
+    }
+
+    fn persist_heartbeat(&self, heartbeat: &HeartbeatRecord) -> Result<()> {
+        let mut heartbeats = self.heartbeats.lock().unwrap();
+        let id = heartbeats.last().map(|h| h.id + 1).unwrap_or(1);
+        heartbeats.push(StoredHeartbeat {
+            id,
+            record: heartbeat.clone(),
+        });
+        Ok(())
+    }
+
+    fn heartbeat_history(&self, query: &HeartbeatQuery) -> Result<Vec<StoredHeartbeat>> {
+        let heartbeats = self.heartbeats.lock().unwrap();
+        let after_id = query.after_id.unwrap_or(0);
+        Ok(heartbeats
+            .iter()
+            .filter(|h| {
+                h.id > after_id
+                    && h.record.mac == query.mac
+                    && h.record.received_at >= query.from
+                    && h.record.received_at < query.to
+            })
+            .take(query.limit)
+            .cloned()
+            .collect())
+    }
+
+    fn latest_heartbeats(&self, mac: &str, limit: usize) -> Result<Vec<StoredHeartbeat>> {
+        let heartbeats = self.heartbeats.lock().unwrap();
+        Ok(heartbeats
+            .iter()
+            .rev()
+            .filter(|h| h.record.mac == mac)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    fn activity_spans(
+        &self,
+        mac: Option<&str>,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        sink: RowSink<'_, ActivitySpan>,
+    ) -> Result<()> {
+        let wanted = |device: &str| mac.is_none_or(|mac| device == mac);
+
+        // Released before the raw heartbeats are locked, the order rollups use
+        let (mut spans, raw_from) = {
+            let hourly = self.hourly.lock().unwrap();
+            let rolled_up_until = hourly
+                .keys()
+                .map(|(_, hour)| *hour + chrono::Duration::hours(1))
+                .max()
+                .filter(|until| *until > from);
+            let spans: Vec<ActivitySpan> = match rolled_up_until {
+                Some(until) => hourly
+                    .values()
+                    .filter(|s| {
+                        wanted(&s.mac)
+                            && s.hour_start >= hour_start(from)
+                            && s.hour_start < until.min(to)
+                    })
+                    .map(|s| ActivitySpan {
+                        mac: s.mac.clone(),
+                        first_seen: s.first_seen,
+                        last_seen: s.last_seen,
+                        heartbeat_count: s.heartbeat_count,
+                        lp: s.max_lp,
+                    })
+                    .collect(),
+                None => Vec::new(),
+            };
+            (spans, rolled_up_until.unwrap_or(from))
+        };
+
+        let heartbeats = self.heartbeats.lock().unwrap();
+        spans.extend(
+            heartbeats
+                .iter()
+                .filter(|h| {
+                    wanted(&h.record.mac)
+                        && h.record.received_at >= raw_from
+                        && h.record.received_at < to
+                })
+                .map(|h| ActivitySpan {
+                    mac: h.record.mac.clone(),
+                    first_seen: h.record.received_at,
+                    last_seen: h.record.received_at,
+                    heartbeat_count: 1,
+                    lp: h.record.lp,
+                }),
+        );
+        drop(heartbeats);
+
+        spans.sort_by(|a, b| (&a.mac, a.first_seen).cmp(&(&b.mac, b.first_seen)));
+        for span in spans {
+            sink(span)?;
+        }
+        Ok(())
+    }
+
+    fn list_devices(&self) -> Result<Vec<DeviceRecord>> {
+        let devices = self.devices.read().unwrap();
+        let mut records: Vec<DeviceRecord> = devices
+            .iter()
+            .map(|(mac, authorization)| DeviceRecord {
+                mac: mac.clone(),
+                account_id: authorization.account_id,
+                active: authorization.authorized,
+                squelched: authorization.squelched,
+            })
+            .collect();
+        records.sort_by(|a, b| a.mac.cmp(&b.mac));
+        Ok(records)
+    }
+
+    fn list_authorized_devices(&self) -> Result<Vec<String>> {
+        let devices = self.devices.read().unwrap();
+        let mut macs: Vec<String> = devices
+            .iter()
+            .filter(|(_, authorization)| authorization.authorized && !authorization.squelched)
+            .map(|(mac, _)| mac.clone())
+            .collect();
+        macs.sort();
+        Ok(macs)
+    }
+
+    fn export_heartbeats(
+        &self,
+        filter: &ExportFilter,
+        sink: RowSink<'_, StoredHeartbeat>,
+    ) -> Result<()> {
+        // Snapshot so the lock is not held while the sink waits on a slow client
+        let heartbeats = self.heartbeats.lock().unwrap().clone();
+        let devices = self.devices.read().unwrap().clone();
+        for heartbeat in heartbeats {
+            let record = &heartbeat.record;
+            let account_matches = filter.account_id.is_none_or(|account_id| {
+                devices
+                    .get(&record.mac)
+                    .is_some_and(|d| d.account_id == Some(account_id))
+            });
+            if filter.from.is_none_or(|from| record.received_at >= from)
+                && filter.to.is_none_or(|to| record.received_at < to)
+                && filter
+                    .mac_prefix
+                    .as_deref()
+                    .is_none_or(|prefix| record.mac.starts_with(prefix))
+                && account_matches
+            {
+                sink(heartbeat)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn export_devices(&self, filter: &ExportFilter, sink: RowSink<'_, DeviceRecord>) -> Result<()> {
+        for device in self.list_devices()? {
+            if filter
+                .account_id
+                .is_none_or(|account_id| device.account_id == Some(account_id))
+                && filter
+                    .mac_prefix
+                    .as_deref()
+                    .is_none_or(|prefix| device.mac.starts_with(prefix))
+            {
+                sink(device)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn oldest_heartbeat(&self) -> Result<Option<DateTime<Utc>>> {
+        let heartbeats = self.heartbeats.lock().unwrap();
+        Ok(heartbeats.iter().map(|h| h.record.received_at).min())
+    }
+
+    fn rollup_heartbeats(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<u64> {
+        let heartbeats = self.heartbeats.lock().unwrap();
+
+        let mut groups: HashMap<(String, DateTime<Utc>), Vec<&HeartbeatRecord>> = HashMap::new();
+        for heartbeat in heartbeats
+            .iter()
+            .filter(|h| h.record.received_at >= from && h.record.received_at < to)
+        {
+            groups
+                .entry((
+                    heartbeat.record.mac.clone(),
+                    hour_start(heartbeat.record.received_at),
+                ))
+                .or_default()
+                .push(&heartbeat.record);
+        }
+
+        let mut hourly = self.hourly.lock().unwrap();
+        let mut written = 0;
+        for ((mac, hour_start), records) in groups {
+            if hourly.contains_key(&(mac.clone(), hour_start)) {
+                continue;
+            }
+            let distinct = |ip: fn(&HeartbeatRecord) -> &str| {
+                records
+                    .iter()
+                    .map(|r| ip(r))
+                    .collect::<std::collections::HashSet<_>>()
+                    .len() as u64
+            };
+            let summary = HourlySummary {
+                mac: mac.clone(),
+                hour_start,
+                device_id: records.iter().map(|r| r.device_id).max().unwrap_or(0),
+                heartbeat_count: records.len() as u64,
+                max_lp: records.iter().filter_map(|r| r.lp).max(),
+                first_seen: records.iter().map(|r| r.received_at).min().unwrap(),
+                last_seen: records.iter().map(|r| r.received_at).max().unwrap(),
+                distinct_reported_ips: distinct(|r| &r.reported_ip),
+                distinct_public_ips: distinct(|r| &r.public_ip),
+            };
+            hourly.insert((mac, hour_start), summary);
+            written += 1;
+        }
+        Ok(written)
+    }
+
+    fn delete_heartbeats_before(&self, cutoff: DateTime<Utc>, limit: usize) -> Result<u64> {
+        let mut heartbeats = self.heartbeats.lock().unwrap();
+        let mut deleted = 0;
+        heartbeats.retain(|h| {
+            if deleted < limit && h.record.received_at < cutoff {
+                deleted += 1;
+                false
+            } else {
+                true
+            }
+        });
+        Ok(deleted as u64)
+    }
+
+    fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+}
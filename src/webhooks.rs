@@ -0,0 +1,725 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use crossbeam::channel::{self, Receiver, Sender};
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use serde::Serialize;
+use serde_json::json;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{Semaphore, mpsc};
+
+use crate::config::{WebhookEventKind, WebhookSubscription, WebhooksConfig};
+use crate::reports::{DEFAULT_EXPECTED_INTERVAL_SECS, OUTAGE_GRACE_FACTOR};
+use crate::store::AuthorizationReason;
+
+/// A device state change, as POSTed to subscribers
+#[derive(Clone, Debug, Serialize)]
+pub struct WebhookEvent {
+    pub id: String,
+    pub event: WebhookEventKind,
+    pub mac: String,
+    pub occurred_at: String,
+    pub details: serde_json::Value,
+}
+
+/// A delivery that ran out of retries, one line of the dead-letter file
+#[derive(Serialize)]
+struct DeadLetter<'a> {
+    url: &'a str,
+    attempts: u32,
+    last_error: &'a str,
+    failed_at: String,
+    event: &'a WebhookEvent,
+}
+
+/// Snapshot of the webhook dispatcher, reported by `/health`
+#[derive(Clone, Debug, Serialize)]
+pub struct WebhookStats {
+    pub subscriptions: usize,
+    pub tracked_devices: usize,
+    pub offline_devices: usize,
+    pub queued: usize,
+    pub delivered: u64,
+    pub failed_attempts: u64,
+    pub dead_lettered: u64,
+    /// `rejected` events not raised because the device raised one recently
+    pub rejected_suppressed: u64,
+    pub last_error: Option<String>,
+}
+
+/// What the dispatcher last saw from a device
+struct SeenDevice {
+    last_seen: DateTime<Utc>,
+    expected_interval: i64,
+    reported_ip: String,
+    public_ip: String,
+    offline: bool,
+}
+
+#[derive(Default)]
+struct WebhookCounters {
+    queued: AtomicUsize,
+    delivered: AtomicU64,
+    failed_attempts: AtomicU64,
+    dead_lettered: AtomicU64,
+    rejected_suppressed: AtomicU64,
+    next_id: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+struct Inner {
+    config: WebhooksConfig,
+    client: reqwest::Client,
+    devices: Mutex<HashMap<String, SeenDevice>>,
+    /// When each device last raised `rejected`
+    rejected: Mutex<HashMap<String, DateTime<Utc>>>,
+    counters: WebhookCounters,
+    deliveries: Arc<Semaphore>,
+    /// Lines for the dead-letter writer thread, so files are never written
+    /// from the async runtime
+    dead_letters: Sender<String>,
+    started_at: i64,
+}
+
+/// Turns heartbeats into device state change events and delivers them
+#[derive(Clone)]
+pub struct WebhookDispatcher {
+    tx: mpsc::Sender<WebhookEvent>,
+    inner: Arc<Inner>,
+}
+
+impl WebhookDispatcher {
+    /// Start the dispatch and offline-check tasks on the current tokio runtime
+    pub fn spawn(config: WebhooksConfig) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_seconds.max(1)))
+            .build()
+            .unwrap_or_else(|e| {
+                warn!(error:% = e; "Failed to build webhook HTTP client, using defaults");
+                reqwest::Client::new()
+            });
+        let (tx, mut rx) = mpsc::channel::<WebhookEvent>(config.queue_capacity.max(1));
+        let (dead_letters, dead_letter_rx) = channel::bounded(config.queue_capacity.max(1));
+        let dispatcher = Self {
+            tx,
+            inner: Arc::new(Inner {
+                deliveries: Arc::new(Semaphore::new(config.max_concurrent_deliveries.max(1))),
+                config,
+                client,
+                devices: Mutex::new(HashMap::new()),
+                rejected: Mutex::new(HashMap::new()),
+                counters: WebhookCounters::default(),
+                dead_letters,
+                started_at: Utc::now().timestamp_millis(),
+            }),
+        };
+
+        let subscriptions = &dispatcher.inner.config.subscriptions;
+        if subscriptions.is_empty() {
+            info!("No webhook subscriptions configured");
+            return dispatcher;
+        }
+        for subscription in subscriptions {
+            info!(
+                url = subscription.url.as_str(),
+                events:serde = subscription.events;
+                "Webhook subscription"
+            );
+        }
+
+        let path = dispatcher.inner.config.dead_letter_path.clone();
+        std::thread::Builder::new()
+            .name("webhook-dead-letter".to_string())
+            .spawn(move || write_dead_letters(&path, dead_letter_rx))
+            .expect("failed to spawn webhook dead-letter thread");
+
+        let inner = dispatcher.inner.clone();
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                inner.counters.queued.fetch_sub(1, Ordering::Relaxed);
+                for subscription in inner.config.subscriptions.iter() {
+                    if !wants(subscription, event.event) {
+                        continue;
+                    }
+                    // Waiting here leaves further events in the bounded queue
+                    let Ok(permit) = inner.deliveries.clone().acquire_owned().await else {
+                        return;
+                    };
+                    let inner = inner.clone();
+                    let subscription = subscription.clone();
+                    let event = event.clone();
+                    // Each delivery retries on its own so one slow receiver
+                    // does not hold up the others
+                    tokio::spawn(async move {
+                        inner.deliver(&subscription, &event).await;
+                        drop(permit);
+                    });
+                }
+            }
+        });
+
+        let checker = dispatcher.clone();
+        tokio::spawn(async move {
+            let interval = Duration::from_secs(checker.inner.config.offline_check_seconds.max(1));
+            loop {
+                tokio::time::sleep(interval).await;
+                checker.check_offline(Utc::now());
+                checker.forget_rejections(Utc::now());
+            }
+        });
+
+        dispatcher
+    }
+
+    /// Record an accepted heartbeat, raising `online` and `ip_changed` events
+    pub fn heartbeat_seen(&self, mac: &str, reported_ip: &str, public_ip: &str, lp: Option<i32>) {
+        if self.inner.config.subscriptions.is_empty() {
+            return;
+        }
+
+        let now = Utc::now();
+        let expected_interval = lp
+            .filter(|lp| *lp > 0)
+            .map(|lp| lp as i64)
+            .unwrap_or(DEFAULT_EXPECTED_INTERVAL_SECS);
+
+        let mut events = Vec::new();
+        {
+            let mut devices = self.inner.devices.lock().unwrap();
+            if let Some(device) = devices.get(mac) {
+                if device.offline {
+                    events.push(self.event(
+                        WebhookEventKind::Online,
+                        mac,
+                        json!({
+                            "last_seen": device.last_seen.to_rfc3339(),
+                            "offline_seconds": (now - device.last_seen).num_seconds(),
+                            "reported_ip": reported_ip,
+                            "public_ip": public_ip,
+                        }),
+                    ));
+                }
+                if device.reported_ip != reported_ip || device.public_ip != public_ip {
+                    events.push(self.event(
+                        WebhookEventKind::IpChanged,
+                        mac,
+                        json!({
+                            "previous_reported_ip": device.reported_ip,
+                            "reported_ip": reported_ip,
+                            "previous_public_ip": device.public_ip,
+                            "public_ip": public_ip,
+                        }),
+                    ));
+                }
+            }
+            devices.insert(
+                mac.to_string(),
+                SeenDevice {
+                    last_seen: now,
+                    expected_interval,
+                    reported_ip: reported_ip.to_string(),
+                    public_ip: public_ip.to_string(),
+                    offline: false,
+                },
+            );
+        }
+
+        for event in events {
+            self.notify(event);
+        }
+    }
+
+    /// Raise a `rejected` event for a heartbeat refused by authorization
+    ///
+    /// A device that keeps retrying raises one event per
+    /// `rejected_interval_seconds`.
+    pub fn device_rejected(&self, mac: &str, reason: AuthorizationReason, message: &str) {
+        if self.inner.config.subscriptions.is_empty() {
+            return;
+        }
+        let now = Utc::now();
+        {
+            let mut rejected = self.inner.rejected.lock().unwrap();
+            if rejected
+                .get(mac)
+                .is_some_and(|last| now - *last < self.rejected_interval())
+            {
+                self.inner
+                    .counters
+                    .rejected_suppressed
+                    .fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            rejected.insert(mac.to_string(), now);
+        }
+        let event = self.event(
+            WebhookEventKind::Rejected,
+            mac,
+            json!({ "reason": reason, "message": message }),
+        );
+        self.notify(event);
+    }
+
+    pub fn stats(&self) -> WebhookStats {
+        let counters = &self.inner.counters;
+        let (tracked_devices, offline_devices) = {
+            let devices = self.inner.devices.lock().unwrap();
+            (
+                devices.len(),
+                devices.values().filter(|d| d.offline).count(),
+            )
+        };
+        WebhookStats {
+            subscriptions: self.inner.config.subscriptions.len(),
+            tracked_devices,
+            offline_devices,
+            queued: counters.queued.load(Ordering::Relaxed),
+            delivered: counters.delivered.load(Ordering::Relaxed),
+            failed_attempts: counters.failed_attempts.load(Ordering::Relaxed),
+            dead_lettered: counters.dead_lettered.load(Ordering::Relaxed),
+            rejected_suppressed: counters.rejected_suppressed.load(Ordering::Relaxed),
+            last_error: counters.last_error.lock().unwrap().clone(),
+        }
+    }
+
+    /// Raise `offline` for devices that missed their expected heartbeats
+    ///
+    /// Uses the same grace period as the availability reports, so a device
+    /// reported offline here also shows up as an outage there.
+    fn check_offline(&self, now: DateTime<Utc>) {
+        let mut events = Vec::new();
+        {
+            let mut devices = self.inner.devices.lock().unwrap();
+            for (mac, device) in devices.iter_mut() {
+                let silent = (now - device.last_seen).num_seconds();
+                if device.offline || silent <= device.expected_interval * OUTAGE_GRACE_FACTOR {
+                    continue;
+                }
+                device.offline = true;
+                events.push(self.event(
+                    WebhookEventKind::Offline,
+                    mac,
+                    json!({
+                        "last_seen": device.last_seen.to_rfc3339(),
+                        "expected_interval_seconds": device.expected_interval,
+                        "reported_ip": device.reported_ip,
+                        "public_ip": device.public_ip,
+                    }),
+                ));
+            }
+        }
+
+        for event in events {
+            info!(mac = event.mac.as_str(); "Device is offline");
+            self.notify(event);
+        }
+    }
+
+    fn rejected_interval(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.inner.config.rejected_interval_seconds as i64)
+    }
+
+    /// Drop rejection times that no longer suppress anything
+    fn forget_rejections(&self, now: DateTime<Utc>) {
+        let interval = self.rejected_interval();
+        self.inner
+            .rejected
+            .lock()
+            .unwrap()
+            .retain(|_, last| now - *last < interval);
+    }
+
+    fn event(&self, kind: WebhookEventKind, mac: &str, details: serde_json::Value) -> WebhookEvent {
+        let seq = self.inner.counters.next_id.fetch_add(1, Ordering::Relaxed);
+        WebhookEvent {
+            id: format!("{}-{}", self.inner.started_at, seq),
+            event: kind,
+            mac: mac.to_string(),
+            occurred_at: Utc::now().to_rfc3339(),
+            details,
+        }
+    }
+
+    /// Queue an event without blocking; dead-letter it if the queue is full
+    fn notify(&self, event: WebhookEvent) {
+        let subscriptions = &self.inner.config.subscriptions;
+        if !subscriptions.iter().any(|s| wants(s, event.event)) {
+            return;
+        }
+
+        self.inner.counters.queued.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = self.tx.try_send(event) {
+            self.inner.counters.queued.fetch_sub(1, Ordering::Relaxed);
+            let event = match e {
+                mpsc::error::TrySendError::Full(event) => event,
+                mpsc::error::TrySendError::Closed(event) => event,
+            };
+            warn!(
+                event:serde = event.event,
+                mac = event.mac.as_str();
+                "Webhook queue full, dead-lettering event"
+            );
+            for subscription in subscriptions.iter().filter(|s| wants(s, event.event)) {
+                self.inner
+                    .dead_letter(&subscription.url, 0, "webhook queue full", &event);
+            }
+        }
+    }
+}
+
+impl Inner {
+    /// POST an event to one subscriber, retrying with exponential backoff
+    async fn deliver(&self, subscription: &WebhookSubscription, event: &WebhookEvent) {
+        let body = match serde_json::to_vec(event) {
+            Ok(body) => body,
+            Err(e) => {
+                error!(event_id = event.id.as_str(), error:% = e; "Failed to serialize webhook event");
+                return;
+            }
+        };
+
+        let max_attempts = self.config.max_attempts.max(1);
+        let mut backoff = Duration::from_millis(self.config.initial_backoff_ms);
+        let max_backoff = Duration::from_millis(self.config.max_backoff_ms);
+        let mut last_error = String::new();
+
+        for attempt in 1..=max_attempts {
+            match self.post(subscription, event, &body).await {
+                Ok(()) => {
+                    self.counters.delivered.fetch_add(1, Ordering::Relaxed);
+                    info!(
+                        event:serde = event.event,
+                        event_id = event.id.as_str(),
+                        mac = event.mac.as_str(),
+                        url = subscription.url.as_str(),
+                        attempt = attempt;
+                        "Webhook delivered"
+                    );
+                    return;
+                }
+                Err(e) => {
+                    last_error = e.to_string();
+                    self.counters
+                        .failed_attempts
+                        .fetch_add(1, Ordering::Relaxed);
+                    *self.counters.last_error.lock().unwrap() = Some(last_error.clone());
+                    warn!(
+                        event_id = event.id.as_str(),
+                        url = subscription.url.as_str(),
+                        attempt = attempt,
+                        max_attempts = max_attempts,
+                        error = last_error.as_str();
+                        "Webhook delivery failed"
+                    );
+                }
+            }
+            if attempt < max_attempts {
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(max_backoff);
+            }
+        }
+
+        self.dead_letter(&subscription.url, max_attempts, &last_error, event);
+    }
+
+    async fn post(
+        &self,
+        subscription: &WebhookSubscription,
+        event: &WebhookEvent,
+        body: &[u8],
+    ) -> Result<()> {
+        let timestamp = Utc::now().timestamp().to_string();
+        let signature = sign(&subscription.secret, &timestamp, body)?;
+
+        let response = self
+            .client
+            .post(&subscription.url)
+            .header("Content-Type", "application/json")
+            .header("X-Webhook-Id", &event.id)
+            .header("X-Webhook-Event", event_name(event.event))
+            .header("X-Webhook-Timestamp", &timestamp)
+            .header("X-Webhook-Signature", format!("sha256={}", signature))
+            .body(body.to_vec())
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            anyhow::bail!("receiver returned {}", status);
+        }
+        Ok(())
+    }
+
+    /// Hand a failed delivery to the dead-letter writer
+    fn dead_letter(&self, url: &str, attempts: u32, last_error: &str, event: &WebhookEvent) {
+        self.counters.dead_lettered.fetch_add(1, Ordering::Relaxed);
+        error!(
+            event:serde = event.event,
+            event_id = event.id.as_str(),
+            mac = event.mac.as_str(),
+            url = url,
+            attempts = attempts,
+            error = last_error;
+            "Dead-lettering webhook event"
+        );
+
+        let entry = DeadLetter {
+            url,
+            attempts,
+            last_error,
+            failed_at: Utc::now().to_rfc3339(),
+            event,
+        };
+        let result = serde_json::to_string(&entry)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| {
+                self.dead_letters
+                    .try_send(line)
+                    .map_err(|_| anyhow::anyhow!("dead-letter writer is backed up"))
+            });
+        if let Err(e) = result {
+            error!(
+                path = self.config.dead_letter_path.as_str(),
+                error:% = e;
+                "Failed to write webhook dead letter"
+            );
+        }
+    }
+}
+
+/// Append dead-letter lines to `path` until every sender is dropped
+fn write_dead_letters(path: &str, lines: Receiver<String>) {
+    while let Ok(line) = lines.recv() {
+        let result = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| {
+                // Whatever queued up meanwhile goes out in the same write
+                writeln!(file, "{}", line)?;
+                for line in lines.try_iter() {
+                    writeln!(file, "{}", line)?;
+                }
+                file.flush()
+            });
+        if let Err(e) = result {
+            error!(path = path, error:% = e; "Failed to write webhook dead letter");
+        }
+    }
+}
+
+fn wants(subscription: &WebhookSubscription, kind: WebhookEventKind) -> bool {
+    subscription.events.is_empty() || subscription.events.contains(&kind)
+}
+
+fn event_name(kind: WebhookEventKind) -> &'static str {
+    match kind {
+        WebhookEventKind::Offline => "offline",
+        WebhookEventKind::Online => "online",
+        WebhookEventKind::IpChanged => "ip_changed",
+        WebhookEventKind::Rejected => "rejected",
+    }
+}
+
+/// Hex HMAC-SHA256 of `<timestamp>.<body>`
+fn sign(secret: &str, timestamp: &str, body: &[u8]) -> Result<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .map_err(|e| anyhow::anyhow!("invalid webhook secret: {}", e))?;
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    Ok(hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, body::Bytes, http::HeaderMap, http::StatusCode, routing::post};
+    use std::collections::VecDeque;
+    use std::time::Instant;
+
+    const SECRET: &str = "test-secret";
+
+    struct Received {
+        at: Instant,
+        headers: HeaderMap,
+        body: Bytes,
+    }
+
+    #[derive(Clone, Default)]
+    struct Receiver {
+        /// Status codes to answer with, in order; 200 once used up
+        statuses: Arc<Mutex<VecDeque<u16>>>,
+        received: Arc<Mutex<Vec<Received>>>,
+    }
+
+    impl Receiver {
+        /// Serve on a local port and return the URL to subscribe
+        async fn start(&self) -> String {
+            let receiver = self.clone();
+            let app = Router::new().route(
+                "/hook",
+                post(move |headers: HeaderMap, body: Bytes| {
+                    let receiver = receiver.clone();
+                    async move {
+                        receiver.received.lock().unwrap().push(Received {
+                            at: Instant::now(),
+                            headers,
+                            body,
+                        });
+                        let status = receiver.statuses.lock().unwrap().pop_front();
+                        StatusCode::from_u16(status.unwrap_or(200)).unwrap()
+                    }
+                }),
+            );
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move { axum::serve(listener, app).await });
+            format!("http://{}/hook", addr)
+        }
+
+        fn count(&self) -> usize {
+            self.received.lock().unwrap().len()
+        }
+    }
+
+    fn config(url: String, dead_letter_path: &std::path::Path) -> WebhooksConfig {
+        WebhooksConfig {
+            subscriptions: vec![WebhookSubscription {
+                url,
+                secret: SECRET.to_string(),
+                events: Vec::new(),
+            }],
+            dead_letter_path: dead_letter_path.to_string_lossy().into_owned(),
+            max_attempts: 3,
+            initial_backoff_ms: 50,
+            max_backoff_ms: 1000,
+            timeout_seconds: 5,
+            ..Default::default()
+        }
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "webhooks-{}-{}-{}.ndjson",
+            name,
+            std::process::id(),
+            Utc::now().timestamp_nanos_opt().unwrap_or_default()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    async fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+        for _ in 0..200 {
+            if done() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out waiting for {}", what);
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_signed() {
+        let receiver = Receiver::default();
+        let url = receiver.start().await;
+        let dispatcher = WebhookDispatcher::spawn(config(url, &temp_path("signed")));
+
+        dispatcher.device_rejected("00:00:00:00:36:01", AuthorizationReason::UnknownDevice, "x");
+        wait_for("delivery", || dispatcher.stats().delivered == 1).await;
+
+        let received = receiver.received.lock().unwrap();
+        let request = &received[0];
+        let header = |name: &str| request.headers[name].to_str().unwrap().to_string();
+        assert_eq!(header("x-webhook-event"), "rejected");
+        let timestamp = header("x-webhook-timestamp");
+        let signature = header("x-webhook-signature");
+        let signature = hex::decode(signature.strip_prefix("sha256=").unwrap()).unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(format!("{}.", timestamp).as_bytes());
+        mac.update(&request.body);
+        mac.verify_slice(&signature).unwrap();
+
+        let event: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(event["mac"], "00:00:00:00:36:01");
+        assert_eq!(event["details"]["reason"], "unknown_device");
+    }
+
+    #[tokio::test]
+    async fn failed_deliveries_are_retried_with_backoff() {
+        let receiver = Receiver::default();
+        receiver.statuses.lock().unwrap().extend([500, 503]);
+        let url = receiver.start().await;
+        let dispatcher = WebhookDispatcher::spawn(config(url, &temp_path("retry")));
+
+        dispatcher.device_rejected("00:00:00:00:36:02", AuthorizationReason::UnknownDevice, "x");
+        wait_for("delivery", || dispatcher.stats().delivered == 1).await;
+
+        let stats = dispatcher.stats();
+        assert_eq!(stats.failed_attempts, 2);
+        assert_eq!(stats.dead_lettered, 0);
+        let received = receiver.received.lock().unwrap();
+        assert_eq!(received.len(), 3);
+        // 50ms, then doubled
+        assert!(received[1].at - received[0].at >= Duration::from_millis(50));
+        assert!(received[2].at - received[1].at >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn exhausted_deliveries_are_dead_lettered() {
+        let receiver = Receiver::default();
+        receiver.statuses.lock().unwrap().extend([500, 500, 500]);
+        let url = receiver.start().await;
+        let path = temp_path("dead-letter");
+        let dispatcher = WebhookDispatcher::spawn(config(url.clone(), &path));
+
+        dispatcher.device_rejected("00:00:00:00:36:03", AuthorizationReason::UnknownDevice, "x");
+        wait_for("dead letter", || {
+            std::fs::read_to_string(&path).is_ok_and(|contents| contents.ends_with('\n'))
+        })
+        .await;
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["url"], url);
+        assert_eq!(lines[0]["attempts"], 3);
+        assert_eq!(
+            lines[0]["last_error"],
+            "receiver returned 500 Internal Server Error"
+        );
+        assert_eq!(lines[0]["event"]["mac"], "00:00:00:00:36:03");
+        assert_eq!(receiver.count(), 3);
+        assert_eq!(dispatcher.stats().dead_lettered, 1);
+    }
+
+    #[tokio::test]
+    async fn repeated_rejections_raise_one_event() {
+        let receiver = Receiver::default();
+        let url = receiver.start().await;
+        let dispatcher = WebhookDispatcher::spawn(config(url, &temp_path("rejected")));
+
+        for _ in 0..3 {
+            dispatcher.device_rejected(
+                "00:00:00:00:36:04",
+                AuthorizationReason::UnknownDevice,
+                "x",
+            );
+        }
+        dispatcher.device_rejected("00:00:00:00:36:05", AuthorizationReason::UnknownDevice, "x");
+        wait_for("deliveries", || dispatcher.stats().delivered == 2).await;
+
+        assert_eq!(dispatcher.stats().rejected_suppressed, 2);
+        assert_eq!(receiver.count(), 2);
+    }
+}
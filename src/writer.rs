@@ -0,0 +1,107 @@
+use anyhow::Result;
+use log::{error, info};
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+use crate::executor::DbExecutor;
+use crate::store::HeartbeatRecord;
+
+/// Snapshot of the background heartbeat writer
+#[derive(Clone, Debug, Serialize)]
+pub struct WriterStats {
+    /// False once the writer task has stopped
+    pub running: bool,
+    pub queued: usize,
+    pub capacity: usize,
+    pub written: u64,
+    pub failed: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Default)]
+struct WriterCounters {
+    queued: AtomicUsize,
+    written: AtomicU64,
+    failed: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+/// Persists heartbeats in the background so `/hbd` never waits on an INSERT
+#[derive(Clone)]
+pub struct HeartbeatWriter {
+    sender: mpsc::Sender<HeartbeatRecord>,
+    counters: Arc<WriterCounters>,
+    capacity: usize,
+}
+
+impl HeartbeatWriter {
+    /// Start the writer task on the current tokio runtime
+    pub fn spawn(db: DbExecutor, capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        let (sender, mut receiver) = mpsc::channel::<HeartbeatRecord>(capacity);
+        let counters = Arc::new(WriterCounters::default());
+
+        let task_counters = counters.clone();
+        tokio::spawn(async move {
+            while let Some(record) = receiver.recv().await {
+                task_counters.queued.fetch_sub(1, Ordering::Relaxed);
+                let mac = record.mac.clone();
+                match db
+                    .run("persist_heartbeat", move |store| {
+                        store.persist_heartbeat(&record)
+                    })
+                    .await
+                {
+                    Ok(()) => {
+                        task_counters.written.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(e) => {
+                        error!("Failed to persist heartbeat for MAC {}: {}", mac, e);
+                        task_counters.failed.fetch_add(1, Ordering::Relaxed);
+                        *task_counters.last_error.lock().unwrap() = Some(e.to_string());
+                    }
+                }
+            }
+            info!("Heartbeat writer stopped");
+        });
+
+        Self {
+            sender,
+            counters,
+            capacity,
+        }
+    }
+
+    /// A writer whose task has already stopped, so every enqueue fails
+    #[cfg(test)]
+    pub fn stopped() -> Self {
+        let (sender, _) = mpsc::channel(1);
+        Self {
+            sender,
+            counters: Arc::default(),
+            capacity: 1,
+        }
+    }
+
+    /// Queue a heartbeat for persistence without waiting for the write
+    pub fn enqueue(&self, record: HeartbeatRecord) -> Result<()> {
+        self.counters.queued.fetch_add(1, Ordering::Relaxed);
+        self.sender.try_send(record).map_err(|e| {
+            self.counters.queued.fetch_sub(1, Ordering::Relaxed);
+            anyhow::anyhow!("Heartbeat writer queue unavailable: {}", e)
+        })
+    }
+
+    pub fn stats(&self) -> WriterStats {
+        WriterStats {
+            running: !self.sender.is_closed(),
+            queued: self.counters.queued.load(Ordering::Relaxed),
+            capacity: self.capacity,
+            written: self.counters.written.load(Ordering::Relaxed),
+            failed: self.counters.failed.load(Ordering::Relaxed),
+            last_error: self.counters.last_error.lock().unwrap().clone(),
+        }
+    }
+}